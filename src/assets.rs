@@ -0,0 +1,195 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha384};
+
+use crate::images::digest;
+
+pub fn src_path_to_dist_path(p: &str) -> String {
+    p.replace("src/", "dist/")
+}
+
+// Url of a hashed file, e.g. /media/icons/x.<hash>.png for src/media/icons/x.png
+pub fn hashed_url(src: &str, hashed: &str) -> String {
+    let dist_path = Path::new(&src_path_to_dist_path(src)).with_file_name(hashed);
+    let url = dist_path.strip_prefix("dist").unwrap().to_str().unwrap();
+    format!("/{url}")
+}
+
+// Subresource integrity value of a file, e.g. sha384-<base64 digest>
+pub fn integrity(path: &Path) -> String {
+    let bytes = fs::read(path).unwrap();
+    format!("sha384-{}", STANDARD.encode(Sha384::digest(bytes)))
+}
+
+fn create_dir_for_file(path: &Path) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+}
+
+// How a source file gets to dist before it is hashed
+pub enum Step<'a> {
+    // Copied as it is, e.g. media
+    Copy,
+    // Content of the dist file made from the source path, e.g. minified css
+    Transform(&'a dyn Fn(&Path) -> Vec<u8>),
+    // Already written to dist by another tool, e.g. a bundler, with this extension
+    Prebuilt { extension: &'a str },
+}
+
+/* e.g. home.<hash>.js for dist/scripts/home.js. The hash is of the content in dist, so unchanged files
+keep their names and stay in browser caches between deploys */
+fn hashed_name(dist_path: &Path) -> String {
+    let hash = &digest(&fs::read(dist_path).unwrap())[..16];
+    let stem = dist_path.file_stem().unwrap().to_str().unwrap();
+    match dist_path.extension() {
+        Some(extension) => format!("{stem}.{hash}.{}", extension.to_str().unwrap()),
+        None => format!("{stem}.{hash}"),
+    }
+}
+
+/* Renames the dist file to its hashed name. A .map file next to it gets the same name with .map,
+and the sourceMappingURL comment and the file field of the map are updated */
+fn rename_to_hashed(dist_path: &Path, hashed_name: &str) {
+    let hashed_path = dist_path.with_file_name(hashed_name);
+    let filename = dist_path.file_name().unwrap().to_str().unwrap();
+    let map_path = dist_path.with_file_name(format!("{filename}.map"));
+    if !map_path.exists() {
+        fs::rename(dist_path, hashed_path).unwrap();
+        return;
+    }
+
+    let content = fs::read_to_string(dist_path).unwrap();
+    fs::write(
+        &hashed_path,
+        content.replace(
+            &format!("sourceMappingURL={filename}.map"),
+            &format!("sourceMappingURL={hashed_name}.map"),
+        ),
+    )
+    .unwrap();
+    fs::remove_file(dist_path).unwrap();
+
+    let hashed_map_path = dist_path.with_file_name(format!("{hashed_name}.map"));
+    let map = fs::read_to_string(&map_path).unwrap();
+    fs::write(
+        &hashed_map_path,
+        map.replace(
+            &format!("\"file\":\"{filename}\""),
+            &format!("\"file\":\"{hashed_name}\""),
+        ),
+    )
+    .unwrap();
+    fs::remove_file(map_path).unwrap();
+}
+
+/* Every asset goes through the same steps: copy or transform it from src to dist, hash it and rename it
+to the hashed name. Returns the hashed file names by source path, e.g. src/styles/a.css -> a.<hash>.css */
+pub fn run_pipeline(files: &[PathBuf], step: &Step) -> HashMap<String, String> {
+    let mut hashes = HashMap::new();
+    for src in files {
+        let src_str = src.to_str().unwrap();
+        let mut dist_path = PathBuf::from(src_path_to_dist_path(src_str));
+        match step {
+            Step::Copy => {
+                create_dir_for_file(&dist_path);
+                fs::copy(src, &dist_path).unwrap();
+            }
+            Step::Transform(transform) => {
+                create_dir_for_file(&dist_path);
+                fs::write(&dist_path, transform(src)).unwrap();
+            }
+            Step::Prebuilt { extension } => {
+                dist_path.set_extension(extension);
+            }
+        }
+        let hashed = hashed_name(&dist_path);
+        rename_to_hashed(&dist_path, &hashed);
+        hashes.insert(src_str.to_string(), hashed);
+    }
+    hashes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Empty project directory in the system temp directory, paths under it map from src/ to dist/ too
+    fn project(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mpa-builder-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("dist")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn transformed_files_are_written_with_hashed_names() {
+        let dir = project("transform");
+        let src = dir.join("src/styles/a.css");
+        fs::create_dir_all(src.parent().unwrap()).unwrap();
+        fs::write(&src, "a { color: red }").unwrap();
+        let transform = |path: &Path| {
+            fs::read_to_string(path)
+                .unwrap()
+                .replace(' ', "")
+                .into_bytes()
+        };
+
+        let key = src.to_str().unwrap().to_string();
+        let hashes = run_pipeline(&[src], &Step::Transform(&transform));
+        let hashed = &hashes[&key];
+        assert_eq!(*hashed, format!("a.{}.css", &digest(b"a{color:red}")[..16]));
+        let dist = dir.join("dist/styles");
+        assert_eq!(
+            fs::read_to_string(dist.join(hashed)).unwrap(),
+            "a{color:red}"
+        );
+        assert!(!dist.join("a.css").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn source_maps_are_renamed_with_their_file() {
+        let dir = project("maps");
+        let src = dir.join("src/scripts/home.ts");
+        let dist = dir.join("dist/scripts");
+        fs::create_dir_all(&dist).unwrap();
+        fs::write(
+            dist.join("home.js"),
+            "go();\n//# sourceMappingURL=home.js.map\n",
+        )
+        .unwrap();
+        fs::write(
+            dist.join("home.js.map"),
+            r#"{"version":3,"file":"home.js"}"#,
+        )
+        .unwrap();
+
+        let key = src.to_str().unwrap().to_string();
+        let hashes = run_pipeline(&[src], &Step::Prebuilt { extension: "js" });
+        let hashed = &hashes[&key];
+        assert_eq!(
+            fs::read_to_string(dist.join(hashed)).unwrap(),
+            format!("go();\n//# sourceMappingURL={hashed}.map\n")
+        );
+        assert_eq!(
+            fs::read_to_string(dist.join(format!("{hashed}.map"))).unwrap(),
+            format!(r#"{{"version":3,"file":"{hashed}"}}"#)
+        );
+        assert!(!dist.join("home.js").exists());
+        assert!(!dist.join("home.js.map").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn hashed_urls_keep_the_directory() {
+        assert_eq!(
+            hashed_url("src/media/icons/x.png", "x.abc.png"),
+            "/media/icons/x.abc.png"
+        );
+    }
+}
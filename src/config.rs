@@ -0,0 +1,228 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use serde::Deserialize;
+
+/* Optional mpa.json in the project root, every field has a default */
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Config {
+    pub intl: IntlConfig,
+    pub scripts: ScriptsConfig,
+    pub source_maps: SourceMapKind,
+    pub auto_inject: AutoInjectConfig,
+    // esbuild style browser targets for scripts and styles, e.g. ["chrome58", "safari11"]
+    pub targets: Option<Vec<String>>,
+    pub images: ImagesConfig,
+    pub html: HtmlConfig,
+    pub csp: CspConfig,
+    pub deploy: DeployConfig,
+    // e.g. "https://example.com", dist/sitemap.xml is written when set
+    pub site_url: Option<String>,
+}
+
+// Same browsers as esbuild's --target used to be
+const DEFAULT_TARGETS: [&str; 4] = ["chrome58", "firefox57", "safari11", "edge16"];
+
+impl Config {
+    pub fn targets(&self) -> Vec<String> {
+        match &self.targets {
+            Some(targets) => targets.clone(),
+            None => DEFAULT_TARGETS.map(String::from).to_vec(),
+        }
+    }
+}
+
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceMapKind {
+    #[default]
+    None,
+    // .map file next to the output, linked with a sourceMappingURL comment
+    External,
+    // Map inside the output as a data url
+    Inline,
+    // .map file without the comment, listed in the asset manifest for error trackers
+    Hidden,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct IntlConfig {
+    // Language rendered to the root of dist, overrides the _default suffix of intl files
+    pub default_language: Option<String>,
+    // Fail build instead of warning when translations are missing
+    pub strict: bool,
+    // In dev mode prefix values that fell back to the default language with [lang]
+    pub mark_fallbacks: bool,
+    // Key is language
+    pub languages: BTreeMap<String, LanguageConfig>,
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct LanguageConfig {
+    // Name for language switchers, defaults to the language code
+    pub name: Option<String>,
+    // ltr or rtl, defaults to the direction of the language's script
+    pub dir: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ScriptsConfig {
+    pub bundler: BundlerKind,
+    // ESM output with shared imports in common chunks, scripts are loaded as modules
+    pub splitting: bool,
+}
+
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BundlerKind {
+    // npx esbuild, needs node and esbuild installed
+    #[default]
+    Esbuild,
+    // Bundles in process, node is only needed for installing npm packages
+    Rolldown,
+}
+
+/* Pages get src/scripts/<page>.js and src/styles/<page>.css without writing the tags,
+e.g. the about page gets about.ts */
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AutoInjectConfig {
+    pub enabled: bool,
+    pub script_loading: ScriptLoading,
+}
+
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScriptLoading {
+    // In head, runs after the document is parsed
+    #[default]
+    Defer,
+    // In head, runs as soon as it is loaded
+    Async,
+    // In head with type="module", deferred by the browser
+    Module,
+    // Plain script tag at the end of body
+    Blocking,
+}
+
+#[derive(Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ImagesConfig {
+    // Resized copies and modern formats of raster images in src/media, used with srcset and <picture>
+    pub responsive: bool,
+    // Widths of the copies, widths larger than the image are skipped
+    pub widths: Vec<u32>,
+    // Formats in addition to the original one
+    pub formats: Vec<ImageFormatKind>,
+    // sizes attribute for images that don't set it
+    pub sizes: String,
+    // width and height of media images for <img> tags without them, so the layout doesn't shift
+    pub dimensions: bool,
+    // loading="lazy" and decoding="async" for <img> tags without data-eager
+    pub lazy: bool,
+    // Lossless recompression of png and jpeg files without metadata, and minified svg files
+    pub optimize: bool,
+}
+
+impl Default for ImagesConfig {
+    fn default() -> Self {
+        ImagesConfig {
+            responsive: false,
+            widths: vec![480, 960, 1920],
+            formats: vec![ImageFormatKind::Webp],
+            sizes: "100vw".to_string(),
+            dimensions: true,
+            lazy: true,
+            optimize: true,
+        }
+    }
+}
+
+#[derive(Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormatKind {
+    Webp,
+    // Smaller than webp but slow to encode, the cache keeps it from slowing down every build
+    Avif,
+}
+
+#[derive(Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct HtmlConfig {
+    // Minified pages in build, dev always writes them as they are rendered
+    pub minify: bool,
+    // Css rules each page uses inlined in a <style>, the stylesheets load without blocking rendering
+    pub critical_css: bool,
+    // integrity and crossorigin for local scripts and stylesheets, on unless turned off
+    pub integrity: bool,
+    // Source paths of files preloaded by every page, e.g. src/media/fonts/inter.woff2
+    pub preload: Vec<String>,
+}
+
+impl Default for HtmlConfig {
+    fn default() -> Self {
+        HtmlConfig {
+            minify: true,
+            critical_css: false,
+            integrity: true,
+            preload: vec![],
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CspConfig {
+    pub enabled: bool,
+    pub output: CspOutput,
+    // Sources by directive, e.g. "img-src": "'self' data:", hashes are added to script-src and style-src
+    pub directives: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CspOutput {
+    // <meta http-equiv> in the <head> of every page, right after <meta charset>
+    #[default]
+    Meta,
+    // dist/_headers with a policy per page, dev uses meta
+    Headers,
+}
+
+// Hosting files that build writes to dist: _headers, _redirects and nginx.conf
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DeployConfig {
+    pub redirects: Vec<Redirect>,
+    // / redirects to the index page of the browser's language when the host can tell it
+    pub language_redirects: bool,
+    // nginx.conf with the same headers and redirects for including in a server block
+    pub nginx: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Redirect {
+    // Path, or a path ending with /* whose rest is :splat in to
+    pub from: String,
+    pub to: String,
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+}
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
+pub fn load_config() -> Config {
+    let config_p = Path::new("mpa.json");
+    if config_p.exists() {
+        let content = fs::read_to_string(config_p).unwrap();
+        serde_json::from_str(&content).unwrap()
+    } else {
+        Config::default()
+    }
+}
@@ -0,0 +1,140 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::CspConfig,
+    html::{attribute, attributes, raw_text_elements, start_tags},
+};
+
+// The live reload client that dev adds to pages polls the refresh server
+const REFRESH_SERVER: &str = "http://localhost:4242";
+
+fn hash(content: &str) -> String {
+    format!("'sha256-{}'", STANDARD.encode(Sha256::digest(content)))
+}
+
+// Attribute value as the browser hashes it, with entities decoded
+fn decode_entities(value: &str) -> String {
+    value
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+fn push_unique(hashes: &mut Vec<String>, hash: String) {
+    if !hashes.contains(&hash) {
+        hashes.push(hash);
+    }
+}
+
+/* Content-Security-Policy of a rendered page without 'unsafe-inline'. Inline <script> and <style> elements
+are allowed by their sha256 hashes, event handler and style attributes by theirs with 'unsafe-hashes'.
+Directives that are not configured get the sources of default-src, which is 'self' by default */
+pub fn content_security_policy(html: &str, config: &CspConfig, dev: bool) -> String {
+    let mut scripts = vec![];
+    let mut styles = vec![];
+    for (tag, content) in raw_text_elements(html, "script") {
+        if attribute(tag, "src").is_none() && !content.is_empty() {
+            push_unique(&mut scripts, hash(content));
+        }
+    }
+    for (_, content) in raw_text_elements(html, "style") {
+        if !content.is_empty() {
+            push_unique(&mut styles, hash(content));
+        }
+    }
+    let mut handlers = vec![];
+    let mut style_attributes = vec![];
+    for tag in start_tags(html) {
+        for (name, value) in attributes(tag) {
+            let value = decode_entities(value.unwrap_or_default());
+            if name.to_lowercase().starts_with("on") {
+                push_unique(&mut handlers, hash(&value));
+            } else if name.eq_ignore_ascii_case("style") {
+                push_unique(&mut style_attributes, hash(&value));
+            }
+        }
+    }
+    for (attribute_hashes, hashes) in [(handlers, &mut scripts), (style_attributes, &mut styles)] {
+        if !attribute_hashes.is_empty() {
+            hashes.push("'unsafe-hashes'".to_string());
+            hashes.extend(attribute_hashes);
+        }
+    }
+
+    let mut directives = vec![(
+        "default-src".to_string(),
+        config
+            .directives
+            .get("default-src")
+            .cloned()
+            .unwrap_or("'self'".to_string()),
+    )];
+    for (name, sources) in config.directives.iter() {
+        if name != "default-src" {
+            directives.push((name.to_string(), sources.to_string()));
+        }
+    }
+    let mut dev_sources = vec![];
+    if dev {
+        dev_sources.push(REFRESH_SERVER.to_string());
+    }
+    for (name, sources) in [
+        ("script-src", scripts),
+        ("style-src", styles),
+        ("connect-src", dev_sources),
+    ] {
+        if sources.is_empty() {
+            continue;
+        }
+        let index = match directives.iter().position(|(n, _)| n == name) {
+            Some(index) => index,
+            None => {
+                directives.push((name.to_string(), directives[0].1.clone()));
+                directives.len() - 1
+            }
+        };
+        directives[index].1 = format!("{} {}", directives[index].1, sources.join(" "));
+    }
+    directives
+        .iter()
+        .map(|(name, sources)| format!("{name} {sources}"))
+        .collect::<Vec<String>>()
+        .join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inline_scripts_and_styles_are_hashed() {
+        // Hash of the example in the CSP spec
+        assert_eq!(
+            hash("alert('Hello, world.');"),
+            "'sha256-qznLcsROx4GACP2dm0UCKCzCG+HiZ1guq6ZZDob/Tng='"
+        );
+        let html = r#"<script>alert('Hello, world.');</script><script src="/a.js"></script><style>a{color:red}</style><button onclick="go(&quot;x&quot;)" style="color: red">Go</button>"#;
+        let mut config = CspConfig::default();
+        config
+            .directives
+            .insert("img-src".to_string(), "'self' data:".to_string());
+        assert_eq!(
+            content_security_policy(html, &config, false),
+            "default-src 'self'; img-src 'self' data:; \
+            script-src 'self' 'sha256-qznLcsROx4GACP2dm0UCKCzCG+HiZ1guq6ZZDob/Tng=' 'unsafe-hashes' 'sha256-lvqDDRHHabLt5CZkuiO+A4+IF2n5Jk22flVtezagqfY='; \
+            style-src 'self' 'sha256-6hWWMOcF+mJesSJGYhmK2lkIIEw9Xc5XKGLPVzh8yxs=' 'unsafe-hashes' 'sha256-NerDAUWfwD31YdZHveMrq0GLjsNFMwxLpZl0dPUeCcw='"
+        );
+    }
+
+    #[test]
+    fn dev_allows_the_refresh_server() {
+        let policy = content_security_policy("<p></p>", &CspConfig::default(), true);
+        assert_eq!(
+            policy,
+            "default-src 'self'; connect-src 'self' http://localhost:4242"
+        );
+    }
+}
@@ -0,0 +1,237 @@
+use std::{collections::BTreeMap, fs};
+
+use crate::config::{DeployConfig, Redirect};
+
+// Every file in these dist directories has a hash in its name, so it never changes
+const HASHED_DIRS: [&str; 3] = ["scripts", "styles", "media"];
+
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+// Pages are checked for changes on every visit
+const NO_CACHE: &str = "no-cache";
+
+// Urls a page in dist is served at, e.g. /en/about.html and /en/about, or /en/index.html and /en/
+fn page_urls(path: &str) -> Vec<String> {
+    let file_url = format!("/{path}");
+    let clean_url = match file_url.strip_suffix("index.html") {
+        Some(dir) => dir.to_string(),
+        None => file_url.trim_end_matches(".html").to_string(),
+    };
+    vec![file_url, clean_url]
+}
+
+/* Redirects from a url that a page has are never used, the hosts serve the page like Netlify does
+without forcing. nginx would also fail on the second location for the url */
+fn is_shadowed(redirect: &Redirect, pages: &[&String]) -> bool {
+    pages
+        .iter()
+        .any(|path| page_urls(path).contains(&redirect.from))
+}
+
+fn used_redirects<'a>(config: &'a DeployConfig, pages: &[&String]) -> Vec<&'a Redirect> {
+    config
+        .redirects
+        .iter()
+        .filter(|redirect| !is_shadowed(redirect, pages))
+        .collect()
+}
+
+// Path as a literal in an nginx regex
+fn regex_escape(path: &str) -> String {
+    let mut escaped = String::new();
+    for c in path.chars() {
+        if "\\.^$|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Headers of a page, with its Content-Security-Policy when it goes to headers
+fn page_headers<'a>(
+    path: &str,
+    policies: &'a BTreeMap<String, String>,
+) -> Vec<(&'static str, &'a str)> {
+    let mut headers = vec![("Cache-Control", NO_CACHE)];
+    if let Some(policy) = policies.get(path) {
+        headers.push(("Content-Security-Policy", policy));
+    }
+    headers
+}
+
+// _headers of Netlify and Cloudflare Pages, a url or a /* pattern followed by its indented headers
+fn headers_file(pages: &[&String], policies: &BTreeMap<String, String>) -> String {
+    let mut file = String::new();
+    for dir in HASHED_DIRS {
+        file.push_str(&format!("/{dir}/*\n  Cache-Control: {IMMUTABLE}\n"));
+    }
+    for path in pages {
+        for url in page_urls(path) {
+            file.push_str(&format!("{url}\n"));
+            for (name, value) in page_headers(path, policies) {
+                file.push_str(&format!("  {name}: {value}\n"));
+            }
+        }
+    }
+    file
+}
+
+/* _redirects of Netlify and Cloudflare Pages. Language redirects use Netlify's Language condition and are
+forced with ! because / has a page, Cloudflare skips them */
+fn redirects_file(
+    pages: &[&String],
+    config: &DeployConfig,
+    language_roots: &[(String, String)],
+) -> String {
+    let mut file = String::new();
+    for redirect in used_redirects(config, pages) {
+        file.push_str(&format!(
+            "{} {} {}\n",
+            redirect.from, redirect.to, redirect.status
+        ));
+    }
+    if config.language_redirects {
+        for (lang, path) in language_roots {
+            file.push_str(&format!("/ {} 302! Language={lang}\n", page_urls(path)[1]));
+        }
+    }
+    file
+}
+
+/* Snippet for the server block of a site with dist as its root. Pages get exact locations so clean urls
+work and each page has its own headers, / redirects by the first language in Accept-Language */
+fn nginx_file(
+    pages: &[&String],
+    policies: &BTreeMap<String, String>,
+    config: &DeployConfig,
+    language_roots: &[(String, String)],
+) -> String {
+    let mut file =
+        String::from("# Generated by mpa-builder, include it in the server block of the site\n");
+    file.push_str("location ~ ^/(_headers|_redirects|nginx\\.conf)$ {\n    return 404;\n}\n");
+    for dir in HASHED_DIRS {
+        file.push_str(&format!(
+            "location ^~ /{dir}/ {{\n    add_header Cache-Control \"{IMMUTABLE}\";\n}}\n"
+        ));
+    }
+    for redirect in used_redirects(config, pages) {
+        // Quoted because a regex can have { and ;
+        let (location, pattern, to) = match redirect.from.strip_suffix('*') {
+            Some(prefix) => {
+                let pattern = format!("\"^{}(.*)$\"", regex_escape(prefix));
+                (
+                    format!("~ {pattern}"),
+                    pattern,
+                    redirect.to.replace(":splat", "$1"),
+                )
+            }
+            None => (
+                format!("= {}", redirect.from),
+                "^".to_string(),
+                redirect.to.clone(),
+            ),
+        };
+        let action = match redirect.status {
+            // Netlify's rewrite, the url stays the same. The pattern captures the splat again
+            200 => format!("rewrite {pattern} {to} last"),
+            status => format!("return {status} {to}"),
+        };
+        file.push_str(&format!("location {location} {{\n    {action};\n}}\n"));
+    }
+    for path in pages {
+        for url in page_urls(path) {
+            file.push_str(&format!("location = {url} {{\n"));
+            if url == "/" && config.language_redirects {
+                for (lang, root) in language_roots {
+                    file.push_str(&format!(
+                        "    if ($http_accept_language ~* \"^{lang}\\b\") {{\n        return 302 {};\n    }}\n",
+                        page_urls(root)[1]
+                    ));
+                }
+            }
+            for (name, value) in page_headers(path, policies) {
+                file.push_str(&format!("    add_header {name} \"{value}\" always;\n"));
+            }
+            file.push_str(&format!("    try_files /{path} =404;\n}}\n"));
+        }
+    }
+    file
+}
+
+/* Writes the hosting files to dist. pages are the paths of the pages in dist, policies their
+Content-Security-Policy when it goes to headers and language_roots the index page of each language
+that is not in the root */
+pub fn write_deploy_files(
+    pages: &[&String],
+    policies: &BTreeMap<String, String>,
+    config: &DeployConfig,
+    language_roots: &[(String, String)],
+) {
+    for redirect in config.redirects.iter() {
+        if is_shadowed(redirect, pages) {
+            println!(
+                "Skipped the redirect from {}, a page has that url",
+                redirect.from
+            );
+        }
+    }
+    fs::write("dist/_headers", headers_file(pages, policies)).unwrap();
+    let redirects = redirects_file(pages, config, language_roots);
+    if !redirects.is_empty() {
+        fs::write("dist/_redirects", redirects).unwrap();
+    }
+    if config.nginx {
+        fs::write(
+            "dist/nginx.conf",
+            nginx_file(pages, policies, config, language_roots),
+        )
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(redirects: &[(&str, &str, u16)]) -> DeployConfig {
+        DeployConfig {
+            redirects: redirects
+                .iter()
+                .map(|(from, to, status)| Redirect {
+                    from: from.to_string(),
+                    to: to.to_string(),
+                    status: *status,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn nginx_splat_rewrites_keep_the_rest_of_the_path() {
+        let config = config(&[
+            ("/blog.v1/*", "/posts/:splat", 200),
+            ("/old/*", "/new/:splat", 301),
+        ]);
+        let file = nginx_file(&[], &BTreeMap::new(), &config, &[]);
+        assert!(file.contains(
+            "location ~ \"^/blog\\.v1/(.*)$\" {\n    rewrite \"^/blog\\.v1/(.*)$\" /posts/$1 last;\n}"
+        ));
+        assert!(file.contains("location ~ \"^/old/(.*)$\" {\n    return 301 /new/$1;\n}"));
+    }
+
+    #[test]
+    fn redirects_from_page_urls_are_skipped() {
+        let about = "about.html".to_string();
+        let config = config(&[("/about", "/team", 301), ("/contact", "/about", 302)]);
+        let pages = [&about];
+        let file = nginx_file(&pages, &BTreeMap::new(), &config, &[]);
+        assert_eq!(file.matches("location = /about {").count(), 1);
+        assert!(file.contains("location = /contact {\n    return 302 /about;\n}"));
+        assert_eq!(
+            redirects_file(&pages, &config, &[]),
+            "/contact /about 302\n"
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use fixed_decimal::{FixedDecimal, FloatPrecision};
+use icu::{
+    calendar::{Date, DateTime},
+    datetime::{options::length, DateFormatter, DateTimeFormatter},
+    decimal::FixedDecimalFormatter,
+    locid::Locale,
+};
+use icu_experimental::dimension::provider::currency::{
+    CurrencyEssentialsV1Marker, PatternSelection, PlaceholderValue,
+};
+use icu_provider::prelude::*;
+use tinystr::TinyAsciiStr;
+use writeable::Writeable;
+
+// Unknown or missing languages format with the root locale
+pub fn data_locale(lang: &str) -> DataLocale {
+    let locale = lang.parse::<Locale>().unwrap_or_default();
+    (&locale).into()
+}
+
+// NaN and infinity can't be formatted
+pub fn format_number(lang: &str, n: f64, decimals: Option<i16>) -> Result<String, String> {
+    let formatter = FixedDecimalFormatter::try_new(&data_locale(lang), Default::default()).unwrap();
+    let mut decimal = FixedDecimal::try_from_f64(n, FloatPrecision::Floating)
+        .map_err(|_| format!("{n} is not a finite number"))?;
+    if let Some(decimals) = decimals {
+        decimal.half_expand(-decimals);
+        decimal.pad_end(-decimals);
+    }
+    Ok(formatter.format_to_string(&decimal))
+}
+
+/* Currency symbol and its placement come from CLDR, the number is formatted like format_number
+with two decimals */
+pub fn format_currency(lang: &str, n: f64, currency: &str) -> Result<String, String> {
+    let code = currency
+        .parse::<TinyAsciiStr<3>>()
+        .map_err(|_| format!("invalid currency code {currency}"))?;
+    let locale = data_locale(lang);
+    let payload: DataPayload<CurrencyEssentialsV1Marker> = icu_experimental::provider::Baked
+        .load(DataRequest {
+            locale: &locale,
+            metadata: Default::default(),
+        })
+        .and_then(DataResponse::take_payload)
+        .map_err(|err| err.to_string())?;
+    let essentials = payload.get();
+
+    let config = essentials
+        .pattern_config_map
+        .get_copied(&code.to_unvalidated())
+        .unwrap_or(essentials.default_pattern_config);
+    let symbol = match config.short_placeholder_value {
+        Some(PlaceholderValue::Index(index)) => essentials
+            .placeholders
+            .get(index.into())
+            .unwrap_or(currency),
+        Some(PlaceholderValue::ISO) | None => currency,
+    };
+    let pattern = match config.short_pattern_selection {
+        PatternSelection::Standard => essentials.standard_pattern.as_ref(),
+        PatternSelection::StandardAlphaNextToNumber => {
+            essentials.standard_alpha_next_to_number_pattern.as_ref()
+        }
+    }
+    .ok_or(format!("no currency pattern for {lang}"))?;
+
+    let formatted = pattern
+        .interpolate((format_number(lang, n, Some(2))?, symbol))
+        .write_to_string()
+        .into_owned();
+    Ok(formatted)
+}
+
+fn date_length(style: &str) -> Result<length::Date, String> {
+    match style {
+        "short" => Ok(length::Date::Short),
+        "medium" => Ok(length::Date::Medium),
+        "long" => Ok(length::Date::Long),
+        "full" => Ok(length::Date::Full),
+        _ => Err(format!("unknown date style {style}")),
+    }
+}
+
+fn time_length(style: &str) -> Result<length::Time, String> {
+    match style {
+        "short" => Ok(length::Time::Short),
+        "medium" => Ok(length::Time::Medium),
+        _ => Err(format!("unknown time style {style}")),
+    }
+}
+
+/* Accepts 2022-09-13 and 2022-09-13T14:05:00 (anything after the seconds is ignored).
+Time is only shown when time_style is given */
+pub fn format_date(
+    lang: &str,
+    value: &str,
+    date_style: &str,
+    time_style: Option<&str>,
+) -> Result<String, String> {
+    let invalid = || format!("invalid date {value}, expected YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS");
+    let number = |from: usize, to: usize| -> Result<u8, String> {
+        value
+            .get(from..to)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(invalid)
+    };
+    let year = value
+        .get(0..4)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(invalid)?;
+    let month = number(5, 7)?;
+    let day = number(8, 10)?;
+    let locale = data_locale(lang);
+
+    match time_style {
+        None => {
+            let date = Date::try_new_iso_date(year, month, day).map_err(|_| invalid())?;
+            let formatter = DateFormatter::try_new_with_length(&locale, date_length(date_style)?)
+                .map_err(|err| err.to_string())?;
+            formatter
+                .format_to_string(&date.to_any())
+                .map_err(|err| err.to_string())
+        }
+        Some(time_style) => {
+            let (hour, minute) = if value.len() > 10 {
+                (number(11, 13)?, number(14, 16)?)
+            } else {
+                (0, 0)
+            };
+            let second = number(17, 19).unwrap_or(0);
+            let date_time = DateTime::try_new_iso_datetime(year, month, day, hour, minute, second)
+                .map_err(|_| invalid())?;
+            let options = length::Bag::from_date_time_style(
+                date_length(date_style)?,
+                time_length(time_style)?,
+            );
+            let formatter = DateTimeFormatter::try_new(&locale, options.into())
+                .map_err(|err| err.to_string())?;
+            formatter
+                .format_to_string(&date_time.to_any())
+                .map_err(|err| err.to_string())
+        }
+    }
+}
+
+// Current UTC time as 2022-09-13T14:05:00Z
+pub fn build_time() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let (days, rest) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // Days to civil date, http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_use_the_separators_of_the_language() {
+        assert_eq!(format_number("en", 1234.5, None).unwrap(), "1,234.5");
+        assert_eq!(
+            format_number("fi", 1234.5, Some(2)).unwrap(),
+            "1\u{a0}234,50"
+        );
+        assert_eq!(format_number("en", 2.345, Some(0)).unwrap(), "2");
+    }
+
+    #[test]
+    fn nan_and_infinity_are_errors() {
+        assert!(format_number("en", f64::NAN, None).is_err());
+        assert!(format_number("en", f64::INFINITY, Some(2)).is_err());
+        assert!(format_currency("en", f64::NEG_INFINITY, "EUR").is_err());
+    }
+
+    #[test]
+    fn currency_symbol_and_placement() {
+        assert_eq!(format_currency("en", 5.0, "EUR").unwrap(), "€5.00");
+        assert_eq!(format_currency("fi", 5.0, "EUR").unwrap(), "5,00\u{a0}€");
+        assert!(format_currency("en", 5.0, "EURO").is_err());
+    }
+}
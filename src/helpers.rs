@@ -0,0 +1,253 @@
+use std::{cell::RefCell, collections::HashMap, sync::Arc};
+
+use mustache::Data;
+use serde_json::{Map, Value};
+
+use crate::{
+    format::{format_currency, format_date, format_number},
+    images::inline_svg,
+    intl::lookup,
+    message::format_message,
+};
+
+/* Helpers are mustache lambdas, the section content is a value or key followed by name=value arguments:
+{{#t}}products count=3{{/t}}
+{{#formatNumber}}price decimals=2{{/formatNumber}}
+{{#formatCurrency}}price currency="EUR"{{/formatCurrency}}
+{{#formatDate}}buildTime style="long" time="short"{{/formatDate}}
+{{#svg}}"icons/menu.svg" class="w-6 fill-current" title="Menu" stripSize=true{{/svg}} */
+pub const HELPERS: [&str; 5] = ["t", "formatNumber", "formatCurrency", "formatDate", "svg"];
+
+// More decimals than a double has
+const MAX_DECIMALS: i64 = 20;
+
+type Helper = fn(&str, &str, &Value, &HashMap<String, Value>) -> Result<String, String>;
+
+pub fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// Number, true, false or quoted string literal, otherwise a variable name
+fn call_value(token: &str, data: &Value) -> Option<Value> {
+    if let Ok(b) = token.parse::<bool>() {
+        Some(Value::Bool(b))
+    } else if token.starts_with('"') && token.ends_with('"') && token.len() > 1 {
+        Some(Value::String(token[1..token.len() - 1].to_string()))
+    } else if let Ok(n) = serde_json::from_str::<serde_json::Number>(token) {
+        Some(Value::Number(n))
+    } else {
+        lookup(data, token).cloned()
+    }
+}
+
+// Splits `key count=3 name="Matti"` into the first token and the arguments
+fn parse_call(text: &str, data: &Value) -> Result<(String, HashMap<String, Value>), String> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    let mut quoted = false;
+    for c in text.trim().chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                token.push(c);
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            c => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+
+    let mut tokens = tokens.into_iter();
+    let first = tokens.next().unwrap_or_default();
+    let mut args = HashMap::new();
+    for token in tokens {
+        let (name, value) = token
+            .split_once('=')
+            .ok_or(format!("invalid argument {token}, expected name=value"))?;
+        let value = call_value(value, data)
+            .ok_or(format!("argument {name} refers to undefined {value}"))?;
+        args.insert(name.to_string(), value);
+    }
+    Ok((first, args))
+}
+
+fn number_value(token: &str, data: &Value) -> Result<f64, String> {
+    match call_value(token, data) {
+        Some(Value::Number(n)) => Ok(n.as_f64().unwrap()),
+        Some(Value::String(s)) => s.parse().map_err(|_| format!("{token} is not a number")),
+        Some(_) => Err(format!("{token} is not a number")),
+        None => Err(format!("{token} is not defined")),
+    }
+}
+
+fn string_arg<'a>(args: &'a HashMap<String, Value>, name: &str) -> Result<Option<&'a str>, String> {
+    match args.get(name) {
+        Some(Value::String(s)) => Ok(Some(s)),
+        Some(_) => Err(format!("argument {name} should be a string")),
+        None => Ok(None),
+    }
+}
+
+fn t(lang: &str, key: &str, data: &Value, args: &HashMap<String, Value>) -> Result<String, String> {
+    match lookup(data, key) {
+        Some(Value::String(message)) => format_message(message, lang, args)
+            .map_err(|err| format!("invalid message {key}: {err}")),
+        // Reported by check_translations already, renders empty like other missing variables
+        _ => Ok(String::new()),
+    }
+}
+
+fn format_number_helper(
+    lang: &str,
+    value: &str,
+    data: &Value,
+    args: &HashMap<String, Value>,
+) -> Result<String, String> {
+    let decimals = match args.get("decimals") {
+        Some(Value::Number(n)) => Some(
+            n.as_i64()
+                .filter(|n| (0..=MAX_DECIMALS).contains(n))
+                .ok_or(format!(
+                    "decimals should be an integer from 0 to {MAX_DECIMALS}"
+                ))? as i16,
+        ),
+        Some(_) => {
+            return Err(format!(
+                "decimals should be an integer from 0 to {MAX_DECIMALS}"
+            ))
+        }
+        None => None,
+    };
+    format_number(lang, number_value(value, data)?, decimals)
+}
+
+fn format_currency_helper(
+    lang: &str,
+    value: &str,
+    data: &Value,
+    args: &HashMap<String, Value>,
+) -> Result<String, String> {
+    let currency = string_arg(args, "currency")?.ok_or("missing argument currency")?;
+    format_currency(lang, number_value(value, data)?, currency)
+}
+
+fn format_date_helper(
+    lang: &str,
+    value: &str,
+    data: &Value,
+    args: &HashMap<String, Value>,
+) -> Result<String, String> {
+    let date = match call_value(value, data) {
+        Some(Value::String(date)) => date,
+        Some(_) => return Err(format!("{value} is not a date")),
+        None => return Err(format!("{value} is not defined")),
+    };
+    let style = string_arg(args, "style")?.unwrap_or("medium");
+    format_date(lang, &date, style, string_arg(args, "time")?)
+}
+
+// Svg from src/media inlined into the page, the only helper whose result is not escaped
+fn svg_helper(
+    _lang: &str,
+    path: &str,
+    data: &Value,
+    args: &HashMap<String, Value>,
+) -> Result<String, String> {
+    let path = match call_value(path, data) {
+        Some(Value::String(path)) => path,
+        Some(_) => return Err(format!("{path} is not a path")),
+        None => return Err(format!("{path} is not defined")),
+    };
+    let strip_size = match args.get("stripSize") {
+        Some(Value::Bool(b)) => *b,
+        Some(_) => return Err("stripSize should be true or false".to_string()),
+        None => false,
+    };
+    inline_svg(
+        &path,
+        string_arg(args, "class")?,
+        string_arg(args, "title")?,
+        strip_size,
+    )
+}
+
+/* Template data with the helpers added, the results are html escaped except for svg */
+pub fn template_data(data: Map<String, Value>, lang: &str) -> Data {
+    let data = Arc::new(Value::Object(data));
+    let mut template_data = match mustache::to_data(&*data).unwrap() {
+        Data::Map(map) => map,
+        _ => unreachable!(),
+    };
+
+    let helpers: [(&'static str, Helper); 5] = [
+        ("t", t),
+        ("formatNumber", format_number_helper),
+        ("formatCurrency", format_currency_helper),
+        ("formatDate", format_date_helper),
+        ("svg", svg_helper),
+    ];
+    for (name, helper) in helpers {
+        let data = data.clone();
+        let lang = lang.to_string();
+        template_data.insert(
+            name.to_string(),
+            Data::Fun(RefCell::new(Box::new(move |text: String| {
+                let result = parse_call(&text, &data)
+                    .and_then(|(first, args)| helper(&lang, &first, &data, &args));
+                match result {
+                    Ok(output) if name == "svg" => output,
+                    Ok(output) => escape_html(&output),
+                    Err(err) => panic!("{name}: {err}"),
+                }
+            }))),
+        );
+    }
+    Data::Map(template_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn call(helper: Helper, text: &str, data: Value) -> Result<String, String> {
+        let (first, args) = parse_call(text, &data)?;
+        helper("en", &first, &data, &args)
+    }
+
+    #[test]
+    fn arguments_are_literals_or_variables() {
+        let data = json!({ "price": 3.5, "count": 2 });
+        let (first, args) =
+            parse_call(r#"price name="Matti Meikäläinen" n=count ok=true"#, &data).unwrap();
+        assert_eq!(first, "price");
+        assert_eq!(args["name"], json!("Matti Meikäläinen"));
+        assert_eq!(args["n"], json!(2));
+        assert_eq!(args["ok"], json!(true));
+        assert!(parse_call("price n=missing", &data).is_err());
+        assert!(parse_call("price decimals", &data).is_err());
+    }
+
+    #[test]
+    fn decimals_out_of_range_are_errors() {
+        let data = json!({ "price": 3.5, "nan": "NaN" });
+        assert_eq!(
+            call(format_number_helper, "price decimals=2", data.clone()).unwrap(),
+            "3.50"
+        );
+        assert!(call(format_number_helper, "price decimals=70000", data.clone()).is_err());
+        assert!(call(format_number_helper, "price decimals=-1", data.clone()).is_err());
+        assert!(call(format_number_helper, "nan", data).is_err());
+    }
+}
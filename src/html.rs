@@ -0,0 +1,438 @@
+use minify_html::{minify, Cfg};
+
+use crate::config::ScriptLoading;
+
+// Inserts tag before the first closing tag, e.g. </head>, or leaves html as it is when there is none
+fn insert_before(html: &mut String, closing_tag: &str, tag: &str) {
+    if let Some(index) = html.find(closing_tag) {
+        html.insert_str(index, tag);
+    }
+}
+
+/* Adds the script and style of the page by their hashed names in dist/scripts and dist/styles.
+Manual tags keep working, a file the page already refers to is not added again */
+pub fn inject_page_assets(
+    html: &str,
+    script: Option<&str>,
+    style: Option<&str>,
+    script_loading: &ScriptLoading,
+) -> String {
+    let mut html = html.to_string();
+    if let Some(style) = style {
+        if !html.contains(&format!("{style}\"")) {
+            let tag = format!("<link rel=\"stylesheet\" href=\"/styles/{style}\" />");
+            insert_before(&mut html, "</head>", &tag);
+        }
+    }
+    if let Some(script) = script {
+        if !html.contains(&format!("{script}\"")) {
+            let src = format!("/scripts/{script}");
+            match script_loading {
+                ScriptLoading::Defer => {
+                    let tag = format!("<script defer src=\"{src}\"></script>");
+                    insert_before(&mut html, "</head>", &tag);
+                }
+                ScriptLoading::Async => {
+                    let tag = format!("<script async src=\"{src}\"></script>");
+                    insert_before(&mut html, "</head>", &tag);
+                }
+                ScriptLoading::Module => {
+                    let tag = format!("<script type=\"module\" src=\"{src}\"></script>");
+                    insert_before(&mut html, "</head>", &tag);
+                }
+                ScriptLoading::Blocking => {
+                    let tag = format!("<script src=\"{src}\"></script>");
+                    insert_before(&mut html, "</body>", &tag);
+                }
+            }
+        }
+    }
+    html
+}
+
+// Index after the > that ends the tag starting at start, > inside quoted values doesn't end it
+fn tag_end(html: &str, start: usize) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in html[start..].char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return Some(start + i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+// Attribute with the range it takes in the tag, from its name to the end of its value
+struct Attribute<'a> {
+    name: &'a str,
+    value: Option<&'a str>,
+    start: usize,
+    end: usize,
+}
+
+fn parse_attributes(tag: &str) -> Vec<Attribute<'_>> {
+    let mut attributes = vec![];
+    let inner_end = tag.len() - tag.strip_suffix("/>").map_or(1, |_| 2);
+    // Skip < and the tag name
+    let mut i = tag[..inner_end]
+        .find(char::is_whitespace)
+        .unwrap_or(inner_end);
+    loop {
+        i += tag[i..inner_end].len() - tag[i..inner_end].trim_start().len();
+        if i >= inner_end {
+            break;
+        }
+        let start = i;
+        let name_end = tag[i..inner_end]
+            .find(|c: char| c.is_whitespace() || c == '=')
+            .map_or(inner_end, |end| i + end);
+        let name = &tag[start..name_end];
+        let after_name = tag[name_end..inner_end].trim_start();
+        let Some(value) = after_name.strip_prefix('=') else {
+            attributes.push(Attribute {
+                name,
+                value: None,
+                start,
+                end: name_end,
+            });
+            i = name_end;
+            continue;
+        };
+        let value_start = inner_end - value.trim_start().len();
+        let (value, end) = match tag[value_start..].chars().next() {
+            Some(q @ ('"' | '\'')) => {
+                let close = tag[value_start + 1..inner_end]
+                    .find(q)
+                    .map_or(inner_end, |close| value_start + 1 + close);
+                (&tag[value_start + 1..close], (close + 1).min(inner_end))
+            }
+            _ => {
+                let end = tag[value_start..inner_end]
+                    .find(char::is_whitespace)
+                    .map_or(inner_end, |end| value_start + end);
+                (&tag[value_start..end], end)
+            }
+        };
+        attributes.push(Attribute {
+            name,
+            value: Some(value),
+            start,
+            end,
+        });
+        i = end;
+    }
+    attributes
+}
+
+/* Attributes of a tag like <img src="a.png" alt='A' hidden>, in order. Values are as they are written,
+without the quotes */
+pub fn attributes(tag: &str) -> Vec<(&str, Option<&str>)> {
+    parse_attributes(tag)
+        .into_iter()
+        .map(|attribute| (attribute.name, attribute.value))
+        .collect()
+}
+
+pub fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    attributes(tag)
+        .into_iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.unwrap_or_default())
+}
+
+// Adds name="value" to the end of the tag, or only name when value is None
+pub fn add_attribute(tag: &str, name: &str, value: Option<&str>) -> String {
+    let end = if tag.ends_with("/>") {
+        tag.len() - 2
+    } else {
+        tag.len() - 1
+    };
+    let head = tag[..end].trim_end();
+    let attribute = match value {
+        Some(value) => format!(" {name}=\"{value}\""),
+        None => format!(" {name}"),
+    };
+    format!("{head}{attribute}{}", &tag[head.len()..])
+}
+
+// Tag without the attribute and the white space before it
+pub fn remove_attribute(tag: &str, name: &str) -> String {
+    match parse_attributes(tag)
+        .into_iter()
+        .find(|attribute| attribute.name.eq_ignore_ascii_case(name))
+    {
+        Some(attribute) => {
+            let start = tag[..attribute.start].trim_end().len();
+            format!("{}{}", &tag[..start], &tag[attribute.end..])
+        }
+        None => tag.to_string(),
+    }
+}
+
+/* Calls rewrite for every <name> tag with the tag and the html before it, a returned string replaces
+the tag. Closing tags and tags with a longer name, e.g. <imgx> for img, are not matched */
+pub fn rewrite_tags(
+    html: &str,
+    name: &str,
+    mut rewrite: impl FnMut(&str, &str) -> Option<String>,
+) -> String {
+    let open = format!("<{name}");
+    let mut rewritten = String::new();
+    let mut from = 0;
+    while let Some(start) = html[from..].find(&open).map(|i| i + from) {
+        let after_name = html[start + open.len()..].chars().next();
+        let end = match tag_end(html, start) {
+            Some(end) if matches!(after_name, Some(c) if c.is_whitespace() || c == '>' || c == '/') => {
+                end
+            }
+            Some(_) => {
+                rewritten.push_str(&html[from..start + open.len()]);
+                from = start + open.len();
+                continue;
+            }
+            None => break,
+        };
+        rewritten.push_str(&html[from..start]);
+        let tag = &html[start..end];
+        match rewrite(tag, &html[..start]) {
+            Some(replacement) => rewritten.push_str(&replacement),
+            None => rewritten.push_str(tag),
+        }
+        from = end;
+    }
+    rewritten.push_str(&html[from..]);
+    rewritten
+}
+
+/* Collapses white space, drops comments and optional quotes and minifies inline scripts and styles.
+<pre> and <textarea> keep their content */
+pub fn minify_page(html: &str) -> String {
+    let cfg = Cfg {
+        keep_html_and_head_opening_tags: true,
+        minify_css: true,
+        minify_js: true,
+        ..Cfg::default()
+    };
+    String::from_utf8(minify(html.as_bytes(), &cfg)).unwrap()
+}
+
+/* Replaces <link rel="stylesheet"> tags with the css from critical(href) in a <style> and a preload that
+applies the whole stylesheet once it has loaded. Links that critical returns None for are left as they are */
+pub fn inline_critical_css(html: &str, mut critical: impl FnMut(&str) -> Option<String>) -> String {
+    rewrite_tags(html, "link", |tag, _| {
+        if !attribute(tag, "rel").is_some_and(|rel| rel.eq_ignore_ascii_case("stylesheet")) {
+            return None;
+        }
+        let css = critical(attribute(tag, "href")?)?;
+        let style = match attribute(tag, "media") {
+            _ if css.is_empty() => String::new(),
+            Some(media) => format!("<style media=\"{media}\">{css}</style>"),
+            None => format!("<style>{css}</style>"),
+        };
+        let mut preload = remove_attribute(tag, "rel");
+        preload = add_attribute(&preload, "rel", Some("preload"));
+        preload = add_attribute(&preload, "as", Some("style"));
+        preload = add_attribute(
+            &preload,
+            "onload",
+            Some("this.onload=null;this.rel='stylesheet'"),
+        );
+        // Without scripts the stylesheet is loaded normally
+        Some(format!("{style}{preload}<noscript>{tag}</noscript>"))
+    })
+}
+
+/* <link rel="preload"> for a file by its url, e.g. /media/fonts/x.<hash>.woff2. Fonts are fetched without
+credentials so they need crossorigin, and module scripts get modulepreload */
+fn preload_link(url: &str, module: bool) -> String {
+    let extension = url.rsplit('.').next().unwrap_or_default();
+    match extension {
+        "woff2" | "woff" | "ttf" | "otf" => format!(
+            "<link rel=\"preload\" href=\"{url}\" as=\"font\" type=\"font/{extension}\" crossorigin />"
+        ),
+        "js" if module => format!("<link rel=\"modulepreload\" href=\"{url}\" />"),
+        "js" => format!("<link rel=\"preload\" href=\"{url}\" as=\"script\" />"),
+        "css" => format!("<link rel=\"preload\" href=\"{url}\" as=\"style\" />"),
+        _ => format!("<link rel=\"preload\" href=\"{url}\" as=\"image\" />"),
+    }
+}
+
+// Preload links for the urls at the end of <head>
+pub fn add_preloads(html: &str, urls: &[String], module: bool) -> String {
+    let links = urls
+        .iter()
+        .map(|url| preload_link(url, module))
+        .collect::<String>();
+    let mut html = html.to_string();
+    insert_before(&mut html, "</head>", &links);
+    html
+}
+
+/* Adds integrity and crossorigin="anonymous" to <script src> tags and to stylesheet, script and style preload
+and modulepreload <link> tags. integrity(url) gives the hash of a local file and None for other urls */
+pub fn add_integrity(html: &str, mut integrity: impl FnMut(&str) -> Option<String>) -> String {
+    let mut with_integrity = |tag: &str, url_attribute: &str| {
+        if attribute(tag, "integrity").is_some() {
+            return None;
+        }
+        let hash = integrity(attribute(tag, url_attribute)?)?;
+        let mut tag = add_attribute(tag, "integrity", Some(&hash));
+        if attribute(&tag, "crossorigin").is_none() {
+            tag = add_attribute(&tag, "crossorigin", Some("anonymous"));
+        }
+        Some(tag)
+    };
+    let html = rewrite_tags(html, "script", |tag, _| with_integrity(tag, "src"));
+    rewrite_tags(&html, "link", |tag, _| {
+        let rel = attribute(tag, "rel").unwrap_or_default().to_lowercase();
+        let preloads_script_or_style =
+            rel == "preload" && matches!(attribute(tag, "as"), Some("script" | "style"));
+        if rel == "stylesheet" || rel == "modulepreload" || preloads_script_or_style {
+            with_integrity(tag, "href")
+        } else {
+            None
+        }
+    })
+}
+
+/* Adds tag to <head> before the tags it may apply to: right after <meta charset>, which has to stay
+first, or at the start of <head> without it. None when the page has no <head> */
+pub fn add_to_head(html: &str, tag: &str) -> Option<String> {
+    let mut added = false;
+    let after_charset = rewrite_tags(html, "meta", |meta, _| {
+        if added || attribute(meta, "charset").is_none() {
+            return None;
+        }
+        added = true;
+        Some(format!("{meta}{tag}"))
+    });
+    if added {
+        return Some(after_charset);
+    }
+    let at_head_start = rewrite_tags(html, "head", |head, _| {
+        (!std::mem::replace(&mut added, true)).then(|| format!("{head}{tag}"))
+    });
+    added.then_some(at_head_start)
+}
+
+// Start of the </name> closing tag at or after from, in any case like browsers match it
+fn closing_tag(html: &str, from: usize, name: &str) -> Option<usize> {
+    let close = format!("</{name}");
+    html.as_bytes()[from..]
+        .windows(close.len())
+        .position(|bytes| bytes.eq_ignore_ascii_case(close.as_bytes()))
+        .map(|i| from + i)
+}
+
+/* Start tags and contents of the <name> elements, e.g. inline scripts. The content is raw text up to the
+closing tag like in <script> and <style> */
+pub fn raw_text_elements<'a>(html: &'a str, name: &str) -> Vec<(&'a str, &'a str)> {
+    let open = format!("<{name}");
+    let mut elements = vec![];
+    let mut from = 0;
+    while let Some(start) = html[from..].find(&open).map(|i| i + from) {
+        let after_name = html[start + open.len()..].chars().next();
+        if !matches!(after_name, Some(c) if c.is_whitespace() || c == '>' || c == '/') {
+            from = start + open.len();
+            continue;
+        }
+        let Some(end) = tag_end(html, start) else {
+            break;
+        };
+        let content_end = closing_tag(html, end, name).unwrap_or(html.len());
+        elements.push((&html[start..end], &html[end..content_end]));
+        from = content_end;
+    }
+    elements
+}
+
+// Every start tag in the html, the contents of <script> and <style> are skipped
+pub fn start_tags(html: &str) -> Vec<&str> {
+    let mut tags = vec![];
+    let mut from = 0;
+    while let Some(start) = html[from..].find('<').map(|i| i + from) {
+        if !html[start + 1..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            from = start + 1;
+            continue;
+        }
+        let Some(end) = tag_end(html, start) else {
+            break;
+        };
+        let tag = &html[start..end];
+        tags.push(tag);
+        from = end;
+        let name = tag[1..]
+            .split(|c: char| c.is_whitespace() || c == '>' || c == '/')
+            .next()
+            .unwrap()
+            .to_lowercase();
+        if name == "script" || name == "style" {
+            from = closing_tag(html, end, &name).unwrap_or(html.len());
+        }
+    }
+    tags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes_keep_their_written_values() {
+        let tag = r#"<img src="a.png" alt='A > B' hidden data-x=1 />"#;
+        assert_eq!(
+            attributes(tag),
+            vec![
+                ("src", Some("a.png")),
+                ("alt", Some("A > B")),
+                ("hidden", None),
+                ("data-x", Some("1")),
+            ]
+        );
+        assert_eq!(attribute(tag, "ALT"), Some("A > B"));
+        assert_eq!(attribute(tag, "hidden"), Some(""));
+        assert_eq!(attribute(tag, "title"), None);
+    }
+
+    #[test]
+    fn raw_text_elements_end_at_their_closing_tag() {
+        let html = r#"<scripts></scripts><script src="a.js"></script><script>if (a < b) {}</SCRIPT><script>x"#;
+        assert_eq!(
+            raw_text_elements(html, "script"),
+            vec![
+                (r#"<script src="a.js">"#, ""),
+                ("<script>", "if (a < b) {}"),
+                ("<script>", "x"),
+            ]
+        );
+    }
+
+    #[test]
+    fn start_tags_skip_script_and_style_contents() {
+        let html = r#"<!doctype html><p class="a>b">1 < 2</p><script>"<b>"</script><STYLE>a>b{}</STYLE><br/>"#;
+        assert_eq!(
+            start_tags(html),
+            vec![r#"<p class="a>b">"#, "<script>", "<STYLE>", "<br/>"]
+        );
+    }
+
+    #[test]
+    fn tags_go_to_head_after_the_charset() {
+        let tag = "<meta name=\"x\">";
+        assert_eq!(
+            add_to_head(
+                r#"<head><title>A</title><meta charset="utf-8"></head>"#,
+                tag
+            )
+            .unwrap(),
+            r#"<head><title>A</title><meta charset="utf-8"><meta name="x"></head>"#
+        );
+        assert_eq!(
+            add_to_head("<html><head lang=fi><title>A</title></head>", tag).unwrap(),
+            r#"<html><head lang=fi><meta name="x"><title>A</title></head>"#
+        );
+        assert_eq!(add_to_head("<header></header>", tag), None);
+    }
+}
@@ -0,0 +1,328 @@
+use std::{collections::HashMap, fs, io::Cursor, path::Path};
+
+use image::{
+    codecs::{avif::AvifEncoder, jpeg::JpegEncoder},
+    imageops::FilterType,
+    DynamicImage, ImageFormat, ImageReader,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{ImageFormatKind, ImagesConfig},
+    helpers::escape_html,
+    html::{add_attribute, attribute, remove_attribute, rewrite_tags},
+    optimize::minify_svg_for_html,
+};
+
+pub const CACHE_DIR: &str = ".mpa-cache";
+
+// Raster images in src/media that get responsive copies, gifs may be animated so they are left out
+pub const RASTER_EXTENSIONS: [&str; 4] = ["png", "jpg", "jpeg", "webp"];
+
+// Quality of webp, avif and jpeg copies
+const QUALITY: u8 = 80;
+
+// Hex sha256 of the content, names files in the cache
+pub fn digest(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+// Image in dist/media with its width, file is next to the original
+pub struct Candidate {
+    pub file: String,
+    pub width: u32,
+}
+
+pub struct ResponsiveImage {
+    // Copies in the original format from smallest to the original itself
+    pub candidates: Vec<Candidate>,
+    // Copies in other formats by mime type, e.g. image/webp
+    pub sources: Vec<(&'static str, Vec<Candidate>)>,
+}
+
+// Key is the hashed file name of the original, e.g. sample.<hash>.png
+pub type ResponsiveImages = HashMap<String, ResponsiveImage>;
+
+// Key is the hashed file name of a media image, Value is its width and height
+pub type ImageSizes = HashMap<String, (u32, u32)>;
+
+// Size from width and height of the root <svg>, or from its viewBox when they are missing or relative
+fn svg_size(svg: &str) -> Option<(u32, u32)> {
+    let start = svg.find("<svg")?;
+    let end = start + svg[start..].find('>')? + 1;
+    let tag = &svg[start..end];
+    let number = |value: &str| {
+        value
+            .strip_suffix("px")
+            .unwrap_or(value)
+            .parse::<f64>()
+            .ok()
+    };
+    let width = attribute(tag, "width").and_then(number);
+    let height = attribute(tag, "height").and_then(number);
+    if let (Some(width), Some(height)) = (width, height) {
+        return Some((width.round() as u32, height.round() as u32));
+    }
+    let view_box = attribute(tag, "viewBox")?
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter_map(|part| part.parse::<f64>().ok())
+        .collect::<Vec<f64>>();
+    match view_box[..] {
+        [_, _, width, height] => Some((width.round() as u32, height.round() as u32)),
+        _ => None,
+    }
+}
+
+// Width and height of an image or an svg, None for other files
+pub fn image_size(path: &Path) -> Option<(u32, u32)> {
+    if path.extension().is_some_and(|extension| extension == "svg") {
+        return svg_size(&fs::read_to_string(path).ok()?);
+    }
+    ImageReader::open(path)
+        .ok()?
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/* Adds width and height of media images to <img> tags that have neither, or the missing one by
+the aspect ratio, so the layout doesn't shift when they load. With config.lazy every <img> gets
+loading="lazy" and decoding="async" unless it sets them or has data-eager, which is removed */
+pub fn img_attributes(html: &str, sizes: &ImageSizes, config: &ImagesConfig) -> String {
+    rewrite_tags(html, "img", |tag, _| {
+        let mut img = tag.to_string();
+        let size = attribute(tag, "src").and_then(|src| sizes.get(src.rsplit('/').next().unwrap()));
+        if let (true, Some((width, height))) = (config.dimensions, size) {
+            let number = |name| attribute(tag, name).map(|value| value.parse::<f64>().ok());
+            match (number("width"), number("height")) {
+                (None, None) => {
+                    img = add_attribute(&img, "width", Some(&width.to_string()));
+                    img = add_attribute(&img, "height", Some(&height.to_string()));
+                }
+                (Some(Some(w)), None) => {
+                    let h = (w * *height as f64 / *width as f64).round();
+                    img = add_attribute(&img, "height", Some(&h.to_string()));
+                }
+                (None, Some(Some(h))) => {
+                    let w = (h * *width as f64 / *height as f64).round();
+                    img = add_attribute(&img, "width", Some(&w.to_string()));
+                }
+                // Relative sizes like 100% are left as they are
+                _ => {}
+            }
+        }
+        if attribute(&img, "data-eager").is_some() {
+            img = remove_attribute(&img, "data-eager");
+        } else if config.lazy {
+            if attribute(&img, "loading").is_none() {
+                img = add_attribute(&img, "loading", Some("lazy"));
+            }
+            if attribute(&img, "decoding").is_none() {
+                img = add_attribute(&img, "decoding", Some("async"));
+            }
+        }
+        Some(img)
+    })
+}
+
+fn encode(image: &DynamicImage, extension: &str) -> Vec<u8> {
+    let mut bytes = vec![];
+    match extension {
+        "webp" => {
+            let rgba = image.to_rgba8();
+            let encoder = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height());
+            bytes = encoder.encode(QUALITY as f32).to_vec();
+        }
+        "avif" => image
+            .write_with_encoder(AvifEncoder::new_with_speed_quality(&mut bytes, 6, QUALITY))
+            .unwrap(),
+        // No alpha in jpeg
+        "jpg" | "jpeg" => DynamicImage::from(image.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, QUALITY))
+            .unwrap(),
+        _ => image
+            .write_to(
+                &mut Cursor::new(&mut bytes),
+                ImageFormat::from_extension(extension).unwrap(),
+            )
+            .unwrap(),
+    }
+    bytes
+}
+
+/* Writes resized copies of the image next to its hashed file in dist, e.g. sample.<hash>-480.png
+and sample.<hash>-480.webp. Copies are cached in .mpa-cache by the digest of the source, so an image
+is only decoded and encoded again when it changes. encoded counts the copies that were not cached */
+pub fn responsive_image(
+    src: &Path,
+    dist_path: &Path,
+    config: &ImagesConfig,
+    encoded: &mut usize,
+) -> ResponsiveImage {
+    let bytes = fs::read(src).unwrap();
+    let digest = digest(&bytes);
+    let (width, _) = ImageReader::new(Cursor::new(&bytes))
+        .with_guessed_format()
+        .unwrap()
+        .into_dimensions()
+        .unwrap_or_else(|e| panic!("failed to read {}: {e}", src.to_str().unwrap()));
+    let extension = src.extension().unwrap().to_str().unwrap().to_lowercase();
+    let stem = dist_path.file_stem().unwrap().to_str().unwrap();
+    let mut widths = config
+        .widths
+        .iter()
+        .copied()
+        .filter(|w| *w < width)
+        .collect::<Vec<u32>>();
+    widths.sort();
+    widths.dedup();
+
+    // Decoded only when some copy is not in the cache
+    let mut image: Option<DynamicImage> = None;
+    let mut copy = |copy_width: u32, copy_extension: &str| {
+        let file = format!("{stem}-{copy_width}.{copy_extension}");
+        let cache_path =
+            Path::new(CACHE_DIR).join(format!("images/{digest}-{copy_width}.{copy_extension}"));
+        if !cache_path.exists() {
+            let image = image.get_or_insert_with(|| image::load_from_memory(&bytes).unwrap());
+            let resized = if copy_width == width {
+                image.clone()
+            } else {
+                image.resize(copy_width, u32::MAX, FilterType::Lanczos3)
+            };
+            fs::create_dir_all(cache_path.parent().unwrap()).unwrap();
+            fs::write(&cache_path, encode(&resized, copy_extension)).unwrap();
+            *encoded += 1;
+        }
+        fs::copy(&cache_path, dist_path.with_file_name(&file)).unwrap();
+        Candidate {
+            file,
+            width: copy_width,
+        }
+    };
+
+    let mut candidates = vec![];
+    for w in widths.iter() {
+        candidates.push(copy(*w, &extension));
+    }
+    candidates.push(Candidate {
+        file: dist_path.file_name().unwrap().to_str().unwrap().to_string(),
+        width,
+    });
+    let mut sources = vec![];
+    for format in config.formats.iter() {
+        let (format_extension, mime) = match format {
+            ImageFormatKind::Webp => ("webp", "image/webp"),
+            ImageFormatKind::Avif => ("avif", "image/avif"),
+        };
+        if format_extension == extension {
+            continue;
+        }
+        let mut format_candidates = vec![];
+        for w in widths.iter().chain([width].iter()) {
+            format_candidates.push(copy(*w, format_extension));
+        }
+        sources.push((mime, format_candidates));
+    }
+    // avif compresses better, the browser picks the first one it supports
+    sources.sort_by_key(|(mime, _)| *mime != "image/avif");
+    ResponsiveImage {
+        candidates,
+        sources,
+    }
+}
+
+/* Adds srcset and sizes to the <img> tags of responsive images and wraps them in <picture> with
+a <source> per format. Images with their own srcset or inside a <picture> are left as they are */
+pub fn responsive_img_tags(html: &str, images: &ResponsiveImages, sizes: &str) -> String {
+    rewrite_tags(html, "img", |tag, before| {
+        if attribute(tag, "srcset").is_some()
+            || before.rfind("<picture") > before.rfind("</picture")
+        {
+            return None;
+        }
+        let src = attribute(tag, "src")?;
+        // Copies are next to the original, so they keep the directory of src
+        let (dir, file) = match src.rsplit_once('/') {
+            Some((dir, file)) => (format!("{dir}/"), file),
+            None => (String::new(), src),
+        };
+        let image = images.get(file)?;
+        let srcset = |candidates: &[Candidate]| {
+            candidates
+                .iter()
+                .map(|c| format!("{dir}{} {}w", c.file, c.width))
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        let mut img = add_attribute(tag, "srcset", Some(&srcset(&image.candidates)));
+        let sizes = match attribute(tag, "sizes") {
+            Some(sizes) => sizes,
+            None => {
+                img = add_attribute(&img, "sizes", Some(sizes));
+                sizes
+            }
+        };
+        if image.sources.is_empty() {
+            return Some(img);
+        }
+        let sources = image
+            .sources
+            .iter()
+            .map(|(mime, candidates)| {
+                format!(
+                    "<source type=\"{mime}\" srcset=\"{}\" sizes=\"{sizes}\" />",
+                    srcset(candidates)
+                )
+            })
+            .collect::<String>();
+        Some(format!("<picture>{sources}{img}</picture>"))
+    })
+}
+
+/* Svg from src/media for inlining into html, minified like the optimized media but without the doctype.
+class is added to the class of the <svg>. With title it gets role="img" and a <title>, without one it
+is hidden from screen readers unless it has its own <title> */
+pub fn inline_svg(
+    path: &str,
+    class: Option<&str>,
+    title: Option<&str>,
+    strip_size: bool,
+) -> Result<String, String> {
+    let src_path =
+        Path::new("src/media").join(path.trim_start_matches("/media/").trim_start_matches('/'));
+    let bytes = fs::read(&src_path).map_err(|_| format!("{path} not found in src/media"))?;
+    let svg = minify_svg_for_html(&bytes).map_err(|err| format!("{path} {err}"))?;
+    let svg = String::from_utf8(svg).map_err(|_| format!("{path} is not utf-8"))?;
+    let has_title = svg.contains("<title");
+    let mut root = true;
+    Ok(rewrite_tags(&svg, "svg", |tag, _| {
+        if !std::mem::take(&mut root) {
+            return None;
+        }
+        let mut svg_tag = tag.to_string();
+        if strip_size {
+            svg_tag = remove_attribute(&svg_tag, "width");
+            svg_tag = remove_attribute(&svg_tag, "height");
+        }
+        if let Some(class) = class {
+            let merged = match attribute(&svg_tag, "class") {
+                Some(own) => format!("{own} {}", escape_html(class)),
+                None => escape_html(class),
+            };
+            svg_tag = add_attribute(&remove_attribute(&svg_tag, "class"), "class", Some(&merged));
+        }
+        match title {
+            Some(title) => {
+                svg_tag = add_attribute(&svg_tag, "role", Some("img"));
+                Some(format!("{svg_tag}<title>{}</title>", escape_html(title)))
+            }
+            None if !has_title => Some(add_attribute(&svg_tag, "aria-hidden", Some("true"))),
+            None => Some(svg_tag),
+        }
+    }))
+}
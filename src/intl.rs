@@ -0,0 +1,636 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use glob::glob;
+use icu::{
+    locid::LanguageIdentifier,
+    locid_transform::{Direction, LocaleDirectionality, LocaleExpander},
+};
+use quick_xml::escape::escape;
+use serde_json::{Map, Value};
+
+use crate::{
+    config::{IntlConfig, LanguageConfig},
+    helpers::HELPERS,
+    html::{add_attribute, attribute, remove_attribute, rewrite_tags},
+    Page,
+};
+
+pub const BUILTIN_VARIABLES: [&str; 7] = [
+    "lang",
+    "dir",
+    "isDefaultLang",
+    "path",
+    "languages",
+    "urls",
+    "buildTime",
+];
+
+pub struct IntlFile {
+    pub path: String,
+    // Keys of the file are under this path, e.g. ["layout", "shared"] for intl/en/layout/shared.json
+    pub namespace: Vec<String>,
+}
+
+pub struct Intl {
+    // Key is language, "default" is the default language again for the root pages
+    pub map: Map<String, Value>,
+    pub default_lang: Option<String>,
+    // Key is language, Value is the files it was read from
+    pub files: BTreeMap<String, Vec<IntlFile>>,
+    // Key is language, Value is the localized slug by page name
+    pub slugs: BTreeMap<String, BTreeMap<String, String>>,
+    // Key is language, from intl.languages in config
+    pub languages: BTreeMap<String, LanguageConfig>,
+}
+
+fn merge(target: &mut Map<String, Value>, source: Map<String, Value>) {
+    for (key, value) in source {
+        match (target.get_mut(&key), value) {
+            (Some(Value::Object(target)), Value::Object(source)) => merge(target, source),
+            (_, value) => {
+                target.insert(key, value);
+            }
+        }
+    }
+}
+
+/* Languages are either one file, intl/en.json, or a directory, intl/en/about.json,
+where the path of the file is the namespace of its keys. Both are merged into one tree per language.
+Default language is intl.defaultLanguage from config or the one with _default suffix, e.g. fi_default.json */
+pub fn load_intl(config: &IntlConfig) -> Intl {
+    let mut intl = Intl {
+        map: Map::new(),
+        default_lang: config.default_language.clone(),
+        files: BTreeMap::new(),
+        slugs: BTreeMap::new(),
+        languages: config.languages.clone(),
+    };
+    // Always have default intl incase translations are not used
+    intl.map
+        .insert("default".to_string(), Value::Object(Map::new()));
+    let intl_p = Path::new("src/intl");
+    if intl_p.exists() {
+        println!("Generating translations");
+        for entry in glob("src/intl/**/*.json").unwrap() {
+            match entry {
+                Ok(path) => {
+                    let path_str = path.to_str().unwrap();
+                    let content = fs::read_to_string(&path).unwrap();
+                    let map: Map<String, Value> = serde_json::from_str(&content).unwrap();
+
+                    let relative_path = path_str.strip_prefix("src/intl/").unwrap();
+                    let mut splitted_path = relative_path
+                        .trim_end_matches(".json")
+                        .split("/")
+                        .map(String::from)
+                        .collect::<Vec<String>>();
+                    let mut lang = splitted_path.remove(0);
+                    if splitted_path.is_empty() {
+                        if let Some(default_lang) = lang.strip_suffix("_default") {
+                            lang = default_lang.to_string();
+                            if intl.default_lang.is_none() {
+                                intl.default_lang = Some(lang.clone());
+                            }
+                        }
+                    }
+
+                    let mut namespaced = map;
+                    for key in splitted_path.iter().rev() {
+                        let mut parent = Map::new();
+                        parent.insert(key.to_string(), Value::Object(namespaced));
+                        namespaced = parent;
+                    }
+                    let lang_map = intl
+                        .map
+                        .entry(lang.clone())
+                        .or_insert(Value::Object(Map::new()));
+                    merge(lang_map.as_object_mut().unwrap(), namespaced);
+                    intl.files.entry(lang).or_default().push(IntlFile {
+                        path: path_str.to_string(),
+                        namespace: splitted_path,
+                    });
+                }
+                Err(_) => panic!("failed to read intl"),
+            }
+        }
+    }
+    // Slugs are not translations, so they don't fall back to the default language or show up as missing
+    for (lang, value) in intl.map.iter_mut() {
+        let slugs = match value.as_object_mut().unwrap().remove("slugs") {
+            Some(Value::Object(slugs)) => slugs,
+            Some(_) => panic!("slugs of {lang} should be an object"),
+            None => continue,
+        };
+        let slugs = slugs
+            .into_iter()
+            .map(|(page_name, slug)| match slug {
+                Value::String(slug) => (page_name, slug.trim_matches('/').to_string()),
+                _ => panic!("slug of {page_name} in {lang} should be a string"),
+            })
+            .collect();
+        intl.slugs.insert(lang.to_string(), slugs);
+    }
+    if let Some(default_lang) = &intl.default_lang {
+        match intl.map.get(default_lang) {
+            Some(default_map) => {
+                let default_map = default_map.clone();
+                intl.map.insert("default".to_string(), default_map);
+            }
+            None if intl.files.is_empty() => intl.default_lang = None,
+            None => panic!("default language {default_lang} has no translations in src/intl"),
+        }
+    }
+    intl
+}
+
+/* File where a key of the language belongs, the one with the longest matching namespace.
+For directories without a matching file it is the file that should be created. Also returns the length
+of its namespace */
+pub fn file_for_key(lang: &str, files: &[IntlFile], key: &str) -> (String, usize) {
+    let parts = key.split('.').collect::<Vec<&str>>();
+    let file = files
+        .iter()
+        .filter(|file| {
+            file.namespace.len() < parts.len()
+                && file.namespace.iter().zip(parts.iter()).all(|(a, b)| a == b)
+        })
+        .max_by_key(|file| file.namespace.len());
+    match file {
+        Some(file) => (file.path.to_string(), file.namespace.len()),
+        None => (format!("src/intl/{lang}/{}.json", parts[0]), 1),
+    }
+}
+
+/* Path of the page in dist, e.g. en/about.html, or fi/meista.html with "slugs": { "about": "meista" } in fi.
+Default language pages are in the root with the slugs of the default language */
+pub fn page_path(intl: &Intl, intl_key: &str, page_name: &str) -> String {
+    let lang = if intl_key == "default" {
+        intl.default_lang.as_deref()
+    } else {
+        Some(intl_key)
+    };
+    let slug = lang
+        .and_then(|lang| intl.slugs.get(lang))
+        .and_then(|slugs| slugs.get(page_name))
+        .map(String::as_str)
+        .unwrap_or(page_name);
+    if intl_key == "default" {
+        format!("{slug}.html")
+    } else {
+        format!("{intl_key}/{slug}.html")
+    }
+}
+
+pub fn page_url(intl: &Intl, intl_key: &str, page_name: &str) -> String {
+    format!("/{}", page_path(intl, intl_key, page_name))
+}
+
+// Url of the page in every language sorted by language, the default language has its root url
+pub fn language_urls(intl: &Intl, page_name: &str) -> Vec<(String, String)> {
+    let mut langs = intl
+        .map
+        .keys()
+        .filter(|lang| *lang != "default")
+        .collect::<Vec<&String>>();
+    langs.sort();
+    langs
+        .into_iter()
+        .map(|lang| {
+            let key = if Some(lang.as_str()) == intl.default_lang.as_deref() {
+                "default"
+            } else {
+                lang
+            };
+            (lang.to_string(), page_url(intl, key, page_name))
+        })
+        .collect()
+}
+
+/* Links written by hand to a page by its name, e.g. href="/about.html" or href="/about", go to the page
+in the language being rendered, e.g. /fi/meista.html. Links with hreflang, like language switchers,
+are left as they are */
+pub fn localize_links(html: &str, intl: &Intl, intl_key: &str, page_names: &[String]) -> String {
+    rewrite_tags(html, "a", |tag, _| {
+        if attribute(tag, "hreflang").is_some() {
+            return None;
+        }
+        let href = attribute(tag, "href")?;
+        let end = href.find(['?', '#']).unwrap_or(href.len());
+        let path = href[..end].strip_prefix('/')?;
+        let name = path.strip_suffix(".html").unwrap_or(path);
+        if !page_names.iter().any(|page_name| page_name == name) {
+            return None;
+        }
+        let url = page_url(intl, intl_key, name);
+        if url == href[..end] {
+            return None;
+        }
+        let href = format!("{url}{}", &href[end..]);
+        Some(add_attribute(
+            &remove_attribute(tag, "href"),
+            "href",
+            Some(&href),
+        ))
+    })
+}
+
+/* sitemap.xml with the localized url of every page in every language. Each url lists the page in the
+other languages as hreflang alternates, x-default is the default language */
+pub fn sitemap(intl: &Intl, page_names: &[String], site_url: &str) -> String {
+    let site_url = site_url.trim_end_matches('/');
+    let absolute = |url: &str| escape(&format!("{site_url}{url}")).to_string();
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\" xmlns:xhtml=\"http://www.w3.org/1999/xhtml\">\n",
+    );
+    for page_name in page_names {
+        let default_url = page_url(intl, "default", page_name);
+        let urls = language_urls(intl, page_name);
+        if urls.is_empty() {
+            xml.push_str(&format!(
+                "  <url><loc>{}</loc></url>\n",
+                absolute(&default_url)
+            ));
+            continue;
+        }
+        let mut alternates = urls
+            .iter()
+            .map(|(lang, url)| {
+                format!(
+                    "<xhtml:link rel=\"alternate\" hreflang=\"{lang}\" href=\"{}\"/>",
+                    absolute(url)
+                )
+            })
+            .collect::<String>();
+        alternates.push_str(&format!(
+            "<xhtml:link rel=\"alternate\" hreflang=\"x-default\" href=\"{}\"/>",
+            absolute(&default_url)
+        ));
+        for (_, url) in urls.iter() {
+            xml.push_str(&format!(
+                "  <url><loc>{}</loc>{alternates}</url>\n",
+                absolute(url)
+            ));
+        }
+    }
+    xml.push_str("</urlset>\n");
+    xml
+}
+
+// Configured direction or the direction of the language's likely script, e.g. rtl for ar
+pub fn language_dir(intl: &Intl, lang: &str) -> String {
+    if let Some(dir) = intl.languages.get(lang).and_then(|lang| lang.dir.as_ref()) {
+        if dir != "ltr" && dir != "rtl" {
+            panic!("dir of {lang} should be ltr or rtl, not {dir}");
+        }
+        return dir.to_string();
+    }
+    let directionality = LocaleDirectionality::new_with_expander(LocaleExpander::new_extended());
+    let direction = lang
+        .parse::<LanguageIdentifier>()
+        .ok()
+        .and_then(|langid| directionality.get(&langid));
+    match direction {
+        Some(Direction::RightToLeft) => "rtl".to_string(),
+        _ => "ltr".to_string(),
+    }
+}
+
+// Adds dir to the <html> tag unless the layout sets it already
+pub fn inject_dir(html: &str, dir: &str) -> String {
+    let start = match html.find("<html") {
+        Some(start) => start,
+        None => return html.to_string(),
+    };
+    let end = match html[start..].find('>') {
+        Some(end) => start + end,
+        None => return html.to_string(),
+    };
+    if html[start..end].contains(" dir=") {
+        return html.to_string();
+    }
+    format!("{} dir=\"{dir}\"{}", &html[..end], &html[end..])
+}
+
+fn language_name(intl: &Intl, lang: &str) -> String {
+    intl.languages
+        .get(lang)
+        .and_then(|lang| lang.name.clone())
+        .unwrap_or(lang.to_string())
+}
+
+/* Built-in variables are: lang, dir, isDefaultLang, path, languages (lang, name, dir, url, isCurrent, isDefault),
+urls (url by page name), buildTime
+Translations with the same name override these */
+pub fn builtin_page_data(
+    page_name: &str,
+    page_names: &[String],
+    intl_key: &str,
+    intl: &Intl,
+    build_time: &str,
+) -> Map<String, Value> {
+    let default_lang = intl.default_lang.as_deref();
+    let current_lang = if intl_key == "default" {
+        default_lang.unwrap_or_default()
+    } else {
+        intl_key
+    };
+    let path = page_url(intl, intl_key, page_name);
+    let languages = language_urls(intl, page_name)
+        .into_iter()
+        .map(|(lang, url)| {
+            let is_default = Some(lang.as_str()) == default_lang;
+            serde_json::json!({
+                "lang": lang,
+                "name": language_name(intl, &lang),
+                "dir": language_dir(intl, &lang),
+                "url": url,
+                "isCurrent": lang == current_lang,
+                "isDefault": is_default,
+            })
+        })
+        .collect::<Vec<Value>>();
+    let urls = page_names
+        .iter()
+        .map(|name| (name.to_string(), page_url(intl, intl_key, name).into()))
+        .collect::<Map<String, Value>>();
+
+    let mut data = Map::new();
+    data.insert("lang".to_string(), current_lang.into());
+    data.insert("dir".to_string(), language_dir(intl, current_lang).into());
+    data.insert(
+        "isDefaultLang".to_string(),
+        (Some(current_lang) == default_lang).into(),
+    );
+    data.insert("path".to_string(), path.into());
+    data.insert("languages".to_string(), languages.into());
+    data.insert("urls".to_string(), urls.into());
+    data.insert("buildTime".to_string(), build_time.into());
+    data
+}
+
+fn fill_missing(
+    target: &mut Map<String, Value>,
+    fallback: &Map<String, Value>,
+    marker: Option<&str>,
+) {
+    for (key, value) in fallback {
+        match (target.get_mut(key), value) {
+            (Some(Value::Object(target)), Value::Object(fallback)) => {
+                fill_missing(target, fallback, marker)
+            }
+            (Some(_), _) => {}
+            (None, Value::Object(fallback)) => {
+                let mut nested = Map::new();
+                fill_missing(&mut nested, fallback, marker);
+                target.insert(key.to_string(), Value::Object(nested));
+            }
+            (None, Value::String(s)) => {
+                let value = match marker {
+                    Some(marker) => format!("[{marker}] {s}"),
+                    None => s.to_string(),
+                };
+                target.insert(key.to_string(), value.into());
+            }
+            (None, value) => {
+                target.insert(key.to_string(), value.clone());
+            }
+        }
+    }
+}
+
+/* Keys missing from a language get the value of the default language.
+With mark the fallback strings are prefixed with the default language, e.g. [fi] Moi */
+pub fn apply_fallbacks(intl: &mut Intl, mark: bool) {
+    let default_lang = match &intl.default_lang {
+        Some(lang) => lang.clone(),
+        None => return,
+    };
+    let fallback = intl.map[&default_lang].as_object().unwrap().clone();
+    let marker = if mark {
+        Some(default_lang.as_str())
+    } else {
+        None
+    };
+    for (lang, value) in intl.map.iter_mut() {
+        if *lang == default_lang || lang == "default" {
+            continue;
+        }
+        fill_missing(value.as_object_mut().unwrap(), &fallback, marker);
+    }
+}
+
+// Dotted paths of all the leaf values, e.g. layout.shared.footerText
+fn flatten_keys(prefix: &str, value: &Value, keys: &mut Vec<String>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let path = if prefix.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                flatten_keys(&path, value, keys);
+            }
+        }
+        _ => keys.push(prefix.to_string()),
+    }
+}
+
+pub fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    let mut current = value;
+    for part in path.split('.') {
+        current = current.get(part)?;
+    }
+    Some(current)
+}
+
+fn has_path(value: &Value, path: &str) -> bool {
+    lookup(value, path).is_some()
+}
+
+// Variables used outside of sections, contents of sections are skipped because the context changes there
+fn template_variables(template: &str) -> Vec<String> {
+    let mut vars: Vec<String> = vec![];
+    let mut depth = 0;
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rest = &rest[start + 2..];
+        let end = match rest.find("}}") {
+            Some(end) => end,
+            None => break,
+        };
+        let triple = rest.starts_with('{');
+        let tag = rest[..end].trim_start_matches('{').trim();
+        rest = &rest[end + 2..];
+        if triple {
+            rest = rest.strip_prefix('}').unwrap_or(rest);
+        }
+        // The message key of a t call counts as a variable
+        if tag == "#t" && depth == 0 {
+            if let Some(close) = rest.find("{{/t}}") {
+                if let Some(key) = rest[..close].split_whitespace().next() {
+                    if !vars.iter().any(|v| v == key) {
+                        vars.push(key.to_string());
+                    }
+                }
+                rest = &rest[close + "{{/t}}".len()..];
+                continue;
+            }
+        }
+        let name = match tag.chars().next() {
+            Some('#') => {
+                depth += 1;
+                if depth > 1 {
+                    continue;
+                }
+                tag[1..].trim()
+            }
+            Some('^') => {
+                depth += 1;
+                continue;
+            }
+            Some('/') => {
+                depth -= 1;
+                continue;
+            }
+            Some('!') | Some('>') | Some('=') | None => continue,
+            Some('&') if depth == 0 => tag[1..].trim(),
+            _ if depth == 0 => tag,
+            _ => continue,
+        };
+        if name != "." && !vars.iter().any(|v| v == name) {
+            vars.push(name.to_string());
+        }
+    }
+    vars
+}
+
+/* Compares every language against the default language and looks for template variables
+that no language defines. Returns the report lines grouped by file, empty when nothing is missing */
+pub fn check_translations(intl: &Intl, layout_html: &str, pages: &[Page]) -> Vec<String> {
+    let mut report = vec![];
+
+    if let Some(default_lang) = &intl.default_lang {
+        let mut default_keys = vec![];
+        flatten_keys("", &intl.map[default_lang], &mut default_keys);
+        for (lang, files) in intl.files.iter() {
+            if lang == default_lang {
+                continue;
+            }
+            let mut missing_by_file: BTreeMap<String, Vec<&String>> = BTreeMap::new();
+            for key in default_keys.iter() {
+                if !has_path(&intl.map[lang], key) {
+                    missing_by_file
+                        .entry(file_for_key(lang, files, key).0)
+                        .or_default()
+                        .push(key);
+                }
+            }
+            for (file, keys) in missing_by_file {
+                report.push(format!("  {file}"));
+                for key in keys {
+                    report.push(format!("    {key}"));
+                }
+            }
+        }
+    }
+
+    let defined_anywhere = |path: &str| intl.map.values().any(|value| has_path(value, path));
+    let is_builtin = |name: &str| {
+        let name = name.split('.').next().unwrap();
+        BUILTIN_VARIABLES.contains(&name) || HELPERS.contains(&name)
+    };
+
+    let mut layout_missing = vec![];
+    for var in template_variables(layout_html) {
+        if var == "content" || is_builtin(&var) {
+            continue;
+        }
+        for page in pages {
+            let page_name = &page.name;
+            if !defined_anywhere(&format!("layout.shared.{var}"))
+                && !defined_anywhere(&format!("layout.{page_name}.{var}"))
+            {
+                layout_missing.push(format!(
+                    "    {{{{ {var} }}}} is not defined for page {page_name}"
+                ));
+            }
+        }
+    }
+    if !layout_missing.is_empty() {
+        report.push("  src/layout.html".to_string());
+        report.append(&mut layout_missing);
+    }
+
+    for page in pages {
+        let page_name = &page.name;
+        let page_missing = template_variables(&page.content)
+            .into_iter()
+            .filter(|var| !is_builtin(var) && !defined_anywhere(&format!("{page_name}.{var}")))
+            .collect::<Vec<String>>();
+        if !page_missing.is_empty() {
+            report.push(format!("  {}", page.path));
+            for var in page_missing {
+                report.push(format!(
+                    "    {{{{ {var} }}}} is not defined in any language"
+                ));
+            }
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    // fi is the default language and has localized slugs, en doesn't
+    fn intl() -> Intl {
+        let map = json!({ "default": {}, "fi": {}, "en": {} });
+        let mut fi_slugs = BTreeMap::new();
+        fi_slugs.insert("about".to_string(), "meista".to_string());
+        Intl {
+            map: map.as_object().unwrap().clone(),
+            default_lang: Some("fi".to_string()),
+            files: BTreeMap::new(),
+            slugs: BTreeMap::from([("fi".to_string(), fi_slugs)]),
+            languages: BTreeMap::new(),
+        }
+    }
+
+    fn page_names() -> Vec<String> {
+        vec!["index".to_string(), "about".to_string()]
+    }
+
+    #[test]
+    fn links_to_pages_are_localized() {
+        let html = r#"<a href="/about.html#team">A</a><a href="/about">B</a><a href="/about.html" hreflang="en">C</a><a href="/other.html">D</a>"#;
+        assert_eq!(
+            localize_links(html, &intl(), "default", &page_names()),
+            r#"<a href="/meista.html#team">A</a><a href="/meista.html">B</a><a href="/about.html" hreflang="en">C</a><a href="/other.html">D</a>"#
+        );
+        assert_eq!(
+            localize_links(
+                r#"<a class="x" href="/about.html">A</a>"#,
+                &intl(),
+                "en",
+                &page_names()
+            ),
+            r#"<a class="x" href="/en/about.html">A</a>"#
+        );
+    }
+
+    #[test]
+    fn sitemap_lists_every_language_with_alternates() {
+        let xml = sitemap(&intl(), &page_names(), "https://example.com/");
+        assert!(xml.contains("<url><loc>https://example.com/meista.html</loc>"));
+        assert!(xml.contains("<url><loc>https://example.com/en/about.html</loc>"));
+        assert!(xml.contains(
+            r#"<xhtml:link rel="alternate" hreflang="en" href="https://example.com/en/about.html"/><xhtml:link rel="alternate" hreflang="fi" href="https://example.com/meista.html"/><xhtml:link rel="alternate" hreflang="x-default" href="https://example.com/meista.html"/>"#
+        ));
+        assert_eq!(xml.matches("<url>").count(), 4);
+    }
+}
@@ -1,29 +1,64 @@
 use std::{
-    collections::HashMap,
-    fmt::format,
+    cell::Cell,
+    collections::{BTreeMap, HashMap},
     fs::{self, File},
-    hash::Hash,
     path::{Path, PathBuf},
-    process::{self, Child, Command},
-    sync::{
-        mpsc::{Receiver, Sender},
-        Arc, Mutex,
-    },
+    process::{self, Child, Command, ExitStatus},
+    sync::{Arc, Mutex},
     thread,
 };
 
+use assets::{hashed_url, integrity, run_pipeline, src_path_to_dist_path, Step};
 use clap::{Parser, Subcommand};
+use config::{load_config, BundlerKind, CspOutput, SourceMapKind};
+use csp::content_security_policy;
+use deploy::write_deploy_files;
 use dialoguer::{theme::ColorfulTheme, Input};
+use format::build_time;
 use glob::glob;
+use helpers::template_data;
+use html::{
+    add_integrity, add_preloads, add_to_head, inject_page_assets, inline_critical_css, minify_page,
+};
+use images::{
+    image_size, img_attributes, responsive_image, responsive_img_tags, ImageSizes,
+    ResponsiveImages, CACHE_DIR, RASTER_EXTENSIONS,
+};
+use intl::{
+    apply_fallbacks, builtin_page_data, check_translations, inject_dir, language_dir, load_intl,
+    localize_links, page_path, sitemap,
+};
 use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher};
-use rand::distributions::{Alphanumeric, DistString};
+use optimize::optimize_media;
+use parcel_sourcemap::SourceMap;
 use refresh_server::start_refresh_server;
 use rust_embed::RustEmbed;
-use serde::Serialize;
+use scripts::{
+    bundle_split_with_rolldown, bundle_with_rolldown, esbuild_sourcemap_arg, esbuild_split_entries,
+    esbuild_splitting_args, module_scripts, SplitEntries, SCRIPT_EXTENSIONS,
+};
 use serde_json::{Map, Value};
+use styles::{
+    add_source_map, critical_css, parse_built_style, process_style, process_tailwind_output,
+};
+use translations::{export_translations, import_translations, TranslationFormat};
 use walkdir::WalkDir;
 
+mod assets;
+mod config;
+mod csp;
+mod deploy;
+mod format;
+mod helpers;
+mod html;
+mod images;
+mod intl;
+mod message;
+mod optimize;
 mod refresh_server;
+mod scripts;
+mod styles;
+mod translations;
 
 #[derive(RustEmbed)]
 #[folder = "init_template/"]
@@ -42,68 +77,43 @@ enum Commands {
     Dev,
     Build,
     New,
+    /// Export translations for translators and import them back
+    Translations {
+        #[clap(subcommand)]
+        command: TranslationsCommand,
+    },
 }
 
-fn src_path_to_dist_path(p: &str) -> String {
-    p.replace("src/", "dist/")
+#[derive(Subcommand)]
+enum TranslationsCommand {
+    /// Write all translations to a CSV file or one XLIFF file per language
+    Export {
+        #[clap(long, value_enum, default_value = "csv")]
+        format: TranslationFormat,
+        /// Defaults to translations.csv or translations.xlf
+        #[clap(short, long)]
+        output: Option<String>,
+    },
+    /// Write translations from .csv, .xlf or .xliff files to src/intl
+    Import {
+        #[clap(required = true)]
+        files: Vec<String>,
+    },
 }
 
-fn copy_dir_all(src: impl AsRef<Path>, dst: impl AsRef<Path>) {
-    fs::create_dir_all(&dst).unwrap();
-    for entry in fs::read_dir(src).unwrap() {
-        let entry = entry.unwrap();
-        let ty = entry.file_type().unwrap();
-        if ty.is_dir() {
-            copy_dir_all(entry.path(), dst.as_ref().join(entry.file_name()));
-        } else {
-            fs::copy(entry.path(), dst.as_ref().join(entry.file_name())).unwrap();
-        }
-    }
+pub struct Page {
+    pub name: String,
+    pub path: String,
+    pub content: String,
 }
 
-fn filename_from_path(path: &PathBuf) -> String {
+fn filename_from_path(path: &Path) -> String {
     let path_str = path.to_str().unwrap();
     path_str.split("/").last().unwrap().to_string()
 }
 
-fn path_replace_filename(path: &PathBuf, to: &String) -> String {
-    let s = path.to_str().unwrap();
-    let filename = filename_from_path(&path);
-    let t = to.as_str();
-    let r = s.replace(&filename, t);
-    r
-}
-
-fn create_dir_for_file(path: &PathBuf) {
-    let str = path.to_str().unwrap();
-    let mut splitted = str.split("/").collect::<Vec<&str>>();
-    splitted.pop();
-
-    fs::create_dir_all(splitted.join("/")).unwrap();
-}
-
-// Key is filepath, Value is filename with hash added
-fn path_to_hash(m: &mut HashMap<String, String>, path: &PathBuf) {
-    let filename = filename_from_path(&path);
-    let splitted = filename.split(".").collect::<Vec<&str>>();
-    let hash = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
-    m.insert(
-        path.to_str().unwrap().to_string(),
-        format!("{}.{}.{}", splitted[0], hash, splitted[1]),
-    );
-}
-
-fn file_to_hashed(path: &PathBuf, hashes: &HashMap<String, String>) {
-    let pathname = path.to_str().unwrap();
-    let dist_path = src_path_to_dist_path(pathname);
-
-    let hash = hashes.get(pathname).unwrap();
-    let p = Path::new(&dist_path).to_path_buf();
-    let path_with_hash = path_replace_filename(&p, hash);
-    fs::rename(dist_path, path_with_hash).unwrap();
-}
-
 fn build(dev: bool) {
+    let config = load_config();
     let dist_path = Path::new("dist");
     if dist_path.exists() {
         fs::remove_dir_all(dist_path).unwrap();
@@ -112,108 +122,114 @@ fn build(dev: bool) {
     fs::create_dir_all(dist_path.join("styles")).unwrap();
     fs::create_dir_all(dist_path.join("scripts")).unwrap();
     fs::create_dir_all(dist_path.join("media")).unwrap();
-    let tailwindhash = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
-    println!("Generating tailwind");
-    run_command_and_wait(
-        "npx",
-        Some(vec![
-            "tailwindcss",
-            "-i",
-            "./src/styles/tailwind.css",
-            "-o",
-            &format!("./dist/styles/tailwind.{tailwindhash}.css"),
-        ]),
-        None,
-    );
+    let targets = config.targets();
+    // Projects without tailwind.css don't need Node for styles
+    if Path::new("src/styles/tailwind.css").exists() {
+        println!("Generating tailwind");
+        run_command_and_wait(
+            "npx",
+            Some(vec![
+                "tailwindcss",
+                "-i",
+                "./src/styles/tailwind.css",
+                "-o",
+                // Hashed with the other styles
+                "./dist/styles/tailwind.css",
+            ]),
+            None,
+        );
+    }
 
     let mut js_hashes = HashMap::new();
+    let mut split_entries = SplitEntries::new();
+    let mut source_maps = vec![];
     let scripts_p = Path::new("src/scripts");
     if scripts_p.exists() {
         println!("Generating js");
-        let mut args = vec!["esbuild".to_string()];
-        for entry in glob("src/scripts/**/*.js").unwrap() {
-            match entry {
-                Ok(path) => {
-                    path_to_hash(&mut js_hashes, &path);
-                    let path_str = path.to_str().unwrap();
-                    args.push(path_str.to_string());
+        let splitting = config.scripts.splitting;
+        let mut entries = vec![];
+        for ext in SCRIPT_EXTENSIONS {
+            for entry in glob(&format!("src/scripts/**/*.{ext}")).unwrap() {
+                match entry {
+                    Ok(path) => {
+                        let path_str = path.to_str().unwrap().to_string();
+                        // Type declarations are not entries
+                        if path_str.ends_with(".d.ts") {
+                            continue;
+                        }
+                        entries.push(path);
+                    }
+                    Err(_) => panic!("failed to read script"),
                 }
-                Err(_) => panic!("failed to read script"),
             }
         }
-        let mut rest = vec![
-            format!("--outdir={}", &dist_path.join("scripts").to_str().unwrap()),
-            "--bundle".to_string(),
-            "--minify".to_string(),
-            "--target=chrome58,firefox57,safari11,edge16".to_string(),
-            "--external:../node_modules/*".to_string(),
-        ];
-        args.append(&mut rest);
-        run_command_and_wait("npx", Some(args.iter().map(AsRef::as_ref).collect()), None);
-        // change dist names to hashed names
-        for entry in glob("src/scripts/**/*.js").unwrap() {
-            match entry {
-                Ok(path) => {
-                    file_to_hashed(&path, &js_hashes);
-                }
-                Err(_) => panic!("failed to read script"),
+        // home.ts and home.js would both be bundled to home.js
+        let mut stems = HashMap::new();
+        for path in entries.iter() {
+            if let Some(other) = stems.insert(path.with_extension(""), path) {
+                panic!(
+                    "{} and {} are both bundled to the same .js file, rename one of them",
+                    other.display(),
+                    path.display()
+                );
             }
         }
-    }
-
-    let mut css_hashes = HashMap::new();
-    println!("Generating css");
-    for entry in glob("src/styles/**/*.css").unwrap() {
-        match entry {
-            Ok(path) => {
-                let path_str = path.to_str().unwrap();
-                if !path_str.ends_with("tailwind.css") {
-                    path_to_hash(&mut css_hashes, &path);
-                    create_dir_for_file(&path);
-                    fs::copy(&path, src_path_to_dist_path(path_str)).unwrap();
-                } else {
-                    css_hashes.insert(
-                        "src/styles/tailwind.css".to_string(),
-                        format!("tailwind.{tailwindhash}.css"),
-                    );
+        let outdir = dist_path.join("scripts").to_str().unwrap().to_string();
+        match config.scripts.bundler {
+            BundlerKind::Esbuild => {
+                let mut args = vec!["esbuild".to_string()];
+                for path in entries.iter() {
+                    args.push(path.to_str().unwrap().to_string());
+                }
+                let mut rest = vec![
+                    format!("--outdir={outdir}"),
+                    "--bundle".to_string(),
+                    "--minify".to_string(),
+                    format!("--target={}", targets.join(",")),
+                    "--external:../node_modules/*".to_string(),
+                ];
+                args.append(&mut rest);
+                if let Some(arg) = esbuild_sourcemap_arg(&config.source_maps) {
+                    args.push(arg);
+                }
+                if splitting {
+                    args.append(&mut esbuild_splitting_args(&outdir));
+                }
+                let status = run_command_and_wait(
+                    "npx",
+                    Some(args.iter().map(AsRef::as_ref).collect()),
+                    None,
+                );
+                // esbuild only fails on syntax errors, types are not checked
+                if !status.success() {
+                    panic!("failed to bundle scripts");
+                }
+                if splitting {
+                    split_entries = esbuild_split_entries(&outdir);
                 }
             }
-            Err(_) => panic!("failed to read style"),
+            BundlerKind::Rolldown if splitting => {
+                split_entries =
+                    bundle_split_with_rolldown(&entries, &outdir, &config.source_maps, &targets)
+            }
+            BundlerKind::Rolldown => {
+                bundle_with_rolldown(&entries, &outdir, &config.source_maps, &targets)
+            }
         }
-    }
-    // change dist names to hashed names
-    for entry in glob("src/styles/**/*.js").unwrap() {
-        match entry {
-            Ok(path) => {
-                file_to_hashed(&path, &css_hashes);
+        if splitting {
+            /* Entries and chunks keep the names the bundler hashed from their content and skip run_pipeline,
+            renaming them would break the imports between them */
+            for (src, entry) in split_entries.iter() {
+                js_hashes.insert(src.to_string(), filename_from_path(Path::new(&entry.file)));
             }
-            Err(_) => panic!("failed to read script"),
+        } else {
+            // Every script is bundled to .js
+            js_hashes = run_pipeline(&entries, &Step::Prebuilt { extension: "js" });
         }
-    }
-
-    let mut intl_map: Map<String, Value> = Map::new();
-    // Always have default intl incase translations are not used
-    intl_map.insert("default".to_string(), Value::Object(Map::new()));
-    let intl_p = Path::new("src/intl");
-    if intl_p.exists() {
-        println!("Generating translations");
-        for entry in glob("src/intl/**/*.json").unwrap() {
+        for entry in glob("dist/scripts/**/*.map").unwrap() {
             match entry {
-                Ok(path) => {
-                    let path_str = path.to_str().unwrap();
-                    let mut map = Map::new();
-                    let content = fs::read_to_string(&path).unwrap();
-                    map = serde_json::from_str(&content).unwrap();
-                    if path_str.ends_with("_default.json") {
-                        intl_map.insert("default".to_string(), Value::Object(map.clone()));
-                    }
-                    let normalized_path = path_str.replace("_default.json", ".json");
-                    let splitted_path = normalized_path.split("/").collect::<Vec<&str>>();
-
-                    let lang = splitted_path.last().unwrap().replace(".json", "");
-                    intl_map.insert(lang.to_string(), Value::Object(map.clone()));
-                }
-                Err(_) => panic!("failed to read intl"),
+                Ok(path) => source_maps.push(path),
+                Err(_) => panic!("failed to read source map"),
             }
         }
     }
@@ -222,33 +238,107 @@ fn build(dev: bool) {
     let mut media_hashes = HashMap::new();
     if media_p.exists() {
         println!("generating media");
+        let mut files = vec![];
         for entry in WalkDir::new("src/media") {
             match entry {
                 Ok(entry) => {
                     if entry.file_type().is_file() {
-                        let path = entry.path().to_path_buf();
-                        let pathname = path.to_str().unwrap();
-                        path_to_hash(&mut media_hashes, &path);
-                        create_dir_for_file(&path);
-                        fs::copy(&path, src_path_to_dist_path(pathname)).unwrap();
+                        files.push(entry.path().to_path_buf());
                     }
                 }
-                Err(_) => panic!("failed to read script"),
+                Err(_) => panic!("failed to read media"),
+            }
+        }
+        if config.images.optimize {
+            let size_before = Cell::new(0);
+            let size_after = Cell::new(0);
+            let optimize = |path: &Path| {
+                let optimized = optimize_media(path);
+                size_before.set(size_before.get() + fs::metadata(path).unwrap().len());
+                size_after.set(size_after.get() + optimized.len() as u64);
+                optimized
+            };
+            media_hashes = run_pipeline(&files, &Step::Transform(&optimize));
+            let (before, after) = (size_before.get(), size_after.get());
+            println!(
+                "Optimized media from {:.1} kB to {:.1} kB, saved {:.1} kB ({}%)",
+                before as f64 / 1024.0,
+                after as f64 / 1024.0,
+                (before - after) as f64 / 1024.0,
+                (before - after) * 100 / before.max(1)
+            );
+        } else {
+            media_hashes = run_pipeline(&files, &Step::Copy);
+        }
+    }
+    let mut image_sizes = ImageSizes::new();
+    if config.images.dimensions {
+        for (src, hashed) in media_hashes.iter() {
+            if let Some(size) = image_size(Path::new(src)) {
+                image_sizes.insert(hashed.to_string(), size);
             }
         }
+    }
+    let mut responsive_images = ResponsiveImages::new();
+    if config.images.responsive {
+        println!("Generating responsive images");
+        let mut encoded = 0;
+        for (src, hashed) in media_hashes.iter() {
+            let src = Path::new(src);
+            let extension = src.extension().unwrap_or_default().to_str().unwrap();
+            if !RASTER_EXTENSIONS.contains(&extension.to_lowercase().as_str()) {
+                continue;
+            }
+            let dist_path =
+                Path::new(&src_path_to_dist_path(src.to_str().unwrap())).with_file_name(hashed);
+            let image = responsive_image(src, &dist_path, &config.images, &mut encoded);
+            responsive_images.insert(hashed.to_string(), image);
+        }
+        println!("Encoded {encoded} image copies, the others were in {CACHE_DIR}");
+    }
+    // Key is source path, Value is url of the hashed file, e.g. /media/x.<hash>.png
+    let media_urls = media_hashes
+        .iter()
+        .map(|(src, hashed)| (src.to_string(), hashed_url(src, hashed)))
+        .collect::<HashMap<String, String>>();
 
-        // change dist names to hashed names
-        for entry in WalkDir::new("src/media") {
-            match entry {
-                Ok(entry) => {
-                    if entry.file_type().is_file() {
-                        file_to_hashed(&entry.path().to_path_buf(), &media_hashes);
-                    }
-                }
-                Err(_) => panic!("failed to read script"),
+    println!("Generating css");
+    let mut styles = vec![];
+    for entry in glob("src/styles/**/*.css").unwrap() {
+        match entry {
+            Ok(path) => styles.push(path),
+            Err(_) => panic!("failed to read style"),
+        }
+    }
+    let process = |path: &Path| {
+        let mut source_map =
+            (config.source_maps != SourceMapKind::None).then(|| SourceMap::new("/"));
+        let css = if path == Path::new("src/styles/tailwind.css") {
+            let css = fs::read_to_string("dist/styles/tailwind.css").unwrap();
+            process_tailwind_output(&css, &targets, &media_urls, source_map.as_mut())
+        } else {
+            process_style(path, &targets, &media_urls, source_map.as_mut())
+        };
+        match source_map {
+            Some(source_map) => {
+                let dist_path = src_path_to_dist_path(path.to_str().unwrap());
+                add_source_map(css, Path::new(&dist_path), source_map, &config.source_maps)
             }
+            None => css,
+        }
+        .into_bytes()
+    };
+    let css_hashes = run_pipeline(&styles, &Step::Transform(&process));
+    for entry in glob("dist/styles/**/*.map").unwrap() {
+        match entry {
+            Ok(path) => source_maps.push(path),
+            Err(_) => panic!("failed to read source map"),
         }
     }
+
+    let mut intl = load_intl(&config.intl);
+    let build_time = build_time();
+
     let favicon_p = Path::new("src/favicon.ico");
     if favicon_p.exists() {
         fs::copy(
@@ -262,61 +352,118 @@ fn build(dev: bool) {
         fs::copy(robots_p, src_path_to_dist_path(robots_p.to_str().unwrap())).unwrap();
     }
 
-    println!("Generating html");
-    let layout_html = fs::read_to_string("src/layout.html").unwrap();
+    let mut pages = vec![];
     for entry in glob("src/pages/**/*.html").unwrap() {
         match entry {
             Ok(path) => {
                 let path_str = path.to_str().unwrap();
                 let splitted_path = path_str.split("/").collect::<Vec<&str>>();
-                let page_name = splitted_path.last().unwrap().replace(".html", "");
-                let page_content = fs::read_to_string(path_str).unwrap();
-                for (key, value) in intl_map.clone().into_iter() {
-                    /* Magic keys are: title */
-                    #[derive(Serialize)]
-                    struct LayoutData {
-                        title: String,
-                        content: String,
-                    }
-                    let layout_map = value
-                        .get("layout")
-                        .unwrap_or(&Value::Object(Map::new()))
-                        .as_object()
-                        .unwrap_or(&Map::new())
-                        .to_owned();
-
-                    let mut layout_data = Map::new();
-                    layout_data.insert("content".to_string(), page_content.clone().into());
-                    let shared_layout_translations = layout_map
-                        .get("shared")
-                        .unwrap_or(&Value::Object(Map::new()))
-                        .as_object()
-                        .unwrap_or(&Map::new())
-                        .to_owned();
-
-                    for (key, value) in shared_layout_translations {
-                        layout_data.insert(key, value);
-                    }
-                    let page_layout_translations = layout_map
-                        .get(&page_name)
-                        .unwrap_or(&Value::Object(Map::new()))
-                        .as_object()
-                        .unwrap_or(&Map::new())
-                        .to_owned();
-                    for (key, value) in page_layout_translations {
-                        layout_data.insert(key, value);
-                    }
-                    let layout_template = mustache::compile_str(&layout_html).unwrap();
-                    let mut layout_bytes = vec![];
-                    layout_template
-                        .render(&mut layout_bytes, &layout_data)
-                        .unwrap();
-                    let mut layout_rendered =
-                        std::str::from_utf8(&layout_bytes).unwrap().to_string();
-                    if dev {
-                        let mut splitted = layout_rendered.split("</body>").collect::<Vec<&str>>();
-                        let mut st = splitted[0].to_owned();
-                        st += "<script>
+                pages.push(Page {
+                    name: splitted_path.last().unwrap().replace(".html", ""),
+                    path: path_str.to_string(),
+                    content: fs::read_to_string(path_str).unwrap(),
+                });
+            }
+            Err(_) => panic!("failed to read page"),
+        }
+    }
+
+    let layout_html = fs::read_to_string("src/layout.html").unwrap();
+    let missing_translations = check_translations(&intl, &layout_html, &pages);
+    if !missing_translations.is_empty() {
+        println!("Missing translations:");
+        for line in missing_translations.iter() {
+            println!("{line}");
+        }
+        if config.intl.strict && !dev {
+            println!("Translations are missing and intl.strict is enabled, aborting build");
+            process::exit(1);
+        }
+    }
+    apply_fallbacks(&mut intl, dev && config.intl.mark_fallbacks);
+
+    println!("Generating html");
+    let page_names = pages
+        .iter()
+        .map(|page| page.name.clone())
+        .collect::<Vec<String>>();
+    let mut page_paths = HashMap::new();
+    // Built stylesheets by their href, parsed once for the critical css of every page
+    let mut style_contents = HashMap::new();
+    if !dev && config.html.critical_css {
+        for (src, hashed) in css_hashes.iter() {
+            let path = Path::new(&src_path_to_dist_path(src)).with_file_name(hashed);
+            style_contents.insert(hashed_url(src, hashed), fs::read_to_string(path).unwrap());
+        }
+    }
+    let built_styles = style_contents
+        .iter()
+        .map(|(href, css)| (href.as_str(), parse_built_style(css)))
+        .collect::<HashMap<_, _>>();
+    // Integrity of local files by url
+    let mut integrities: HashMap<String, Option<String>> = HashMap::new();
+    // Content-Security-Policy by page path for dist/_headers
+    let mut csp_headers = BTreeMap::new();
+    // Language and path of the index page of the languages that are not in the root
+    let mut language_roots = vec![];
+    let preload_urls = config
+        .html
+        .preload
+        .iter()
+        .map(|src| {
+            let hashed = [&js_hashes, &css_hashes, &media_hashes]
+                .iter()
+                .find_map(|hashes| hashes.get(src))
+                .unwrap_or_else(|| {
+                    panic!("can't preload {src}, it is not a script, style or media file")
+                });
+            hashed_url(src, hashed)
+        })
+        .collect::<Vec<String>>();
+    for page in pages.iter() {
+        let page_name = &page.name;
+        let page_content = &page.content;
+        for (key, value) in intl.map.clone().into_iter() {
+            let builtins = builtin_page_data(page_name, &page_names, &key, &intl, &build_time);
+            let lang = builtins["lang"].as_str().unwrap().to_string();
+            let layout_map = value
+                .get("layout")
+                .unwrap_or(&Value::Object(Map::new()))
+                .as_object()
+                .unwrap_or(&Map::new())
+                .to_owned();
+
+            let mut layout_data = builtins.clone();
+            layout_data.insert("content".to_string(), page_content.clone().into());
+            let shared_layout_translations = layout_map
+                .get("shared")
+                .unwrap_or(&Value::Object(Map::new()))
+                .as_object()
+                .unwrap_or(&Map::new())
+                .to_owned();
+
+            for (key, value) in shared_layout_translations {
+                layout_data.insert(key, value);
+            }
+            let page_layout_translations = layout_map
+                .get(page_name)
+                .unwrap_or(&Value::Object(Map::new()))
+                .as_object()
+                .unwrap_or(&Map::new())
+                .to_owned();
+            for (key, value) in page_layout_translations {
+                layout_data.insert(key, value);
+            }
+            let layout_template = mustache::compile_str(&layout_html).unwrap();
+            let mut layout_bytes = vec![];
+            layout_template
+                .render_data(&mut layout_bytes, &template_data(layout_data, &lang))
+                .unwrap();
+            let mut layout_rendered = std::str::from_utf8(&layout_bytes).unwrap().to_string();
+            if dev {
+                let mut splitted = layout_rendered.split("</body>").collect::<Vec<&str>>();
+                let mut st = splitted[0].to_owned();
+                st += "<script>
                             let token = sessionStorage.getItem('refresherToken');
                             if (token === null) {
                                 token = '0'
@@ -335,73 +482,186 @@ fn build(dev: bool) {
                             }
                             refresher()
                         </script>";
-                        splitted[0] = &st;
-                        layout_rendered = splitted.join("</body>");
-                    }
-                    let page_template = mustache::compile_str(&layout_rendered).unwrap();
-                    let mut page_bytes = vec![];
-                    let page_data = value
-                        .get(&page_name)
-                        .unwrap_or(&Value::Object(Map::new()))
-                        .to_owned();
-                    page_template.render(&mut page_bytes, &page_data).unwrap();
-                    let mut page_str = std::str::from_utf8(&page_bytes).unwrap().to_string();
-                    // Replace all imports with hashed import
-                    for (key, value) in js_hashes.iter() {
-                        let path = Path::new(&key).to_path_buf();
-                        let filename = filename_from_path(&path);
-                        // This might cause problems some day by replacing some text also, but whatever
-                        let from1 = format!(r#"{}""#, filename);
-                        let to1 = format!(r#"{}""#, value);
-                        let from2 = format!(r#"{}>"#, filename);
-                        let to2 = format!(r#"{}>"#, value);
-                        let from3 = format!(r#"{}/>"#, filename);
-                        let to3 = format!(r#"{}/>"#, value);
-                        page_str = page_str.replace(&from1, &to1);
-                        page_str = page_str.replace(&from2, &to2);
-                        page_str = page_str.replace(&from3, &to3);
-                    }
-                    for (key, value) in css_hashes.iter() {
-                        let path = Path::new(&key).to_path_buf();
-                        let filename = filename_from_path(&path);
-                        // This might cause problems some day by replacing some text also, but whatever
-                        let from1 = format!(r#"{}""#, filename);
-                        let to1 = format!(r#"{}""#, value);
-                        let from2 = format!(r#"{}>"#, filename);
-                        let to2 = format!(r#"{}>"#, value);
-                        let from3 = format!(r#"{}/>"#, filename);
-                        let to3 = format!(r#"{}/>"#, value);
-                        page_str = page_str.replace(&from1, &to1);
-                        page_str = page_str.replace(&from2, &to2);
-                        page_str = page_str.replace(&from3, &to3);
-                    }
-                    for (key, value) in media_hashes.iter() {
-                        let path = Path::new(&key).to_path_buf();
-                        let filename = filename_from_path(&path);
-                        // This might cause problems some day by replacing some text also, but whatever
-                        let from1 = format!(r#"{}""#, filename);
-                        let to1 = format!(r#"{}""#, value);
-                        let from2 = format!(r#"{}>"#, filename);
-                        let to2 = format!(r#"{}>"#, value);
-                        let from3 = format!(r#"{}/>"#, filename);
-                        let to3 = format!(r#"{}/>"#, value);
-                        page_str = page_str.replace(&from1, &to1);
-                        page_str = page_str.replace(&from2, &to2);
-                        page_str = page_str.replace(&from3, &to3);
-                    }
-                    let mut path = dist_path.to_owned();
-                    if key != "default" {
-                        path = path.join(key);
-                        fs::create_dir_all(&path).unwrap();
+                splitted[0] = &st;
+                layout_rendered = splitted.join("</body>");
+            }
+            let page_template = mustache::compile_str(&layout_rendered).unwrap();
+            let mut page_bytes = vec![];
+            let mut page_data = builtins;
+            if let Some(Value::Object(page_translations)) = value.get(page_name) {
+                for (key, value) in page_translations {
+                    page_data.insert(key.clone(), value.clone());
+                }
+            }
+            page_template
+                .render_data(&mut page_bytes, &template_data(page_data, &lang))
+                .unwrap();
+            let mut page_str = std::str::from_utf8(&page_bytes).unwrap().to_string();
+            page_str = inject_dir(&page_str, &language_dir(&intl, &lang));
+            page_str = localize_links(&page_str, &intl, &key, &page_names);
+            // Replace all imports with hashed import
+            for (key, value) in js_hashes.iter() {
+                let path = Path::new(&key).to_path_buf();
+                // Pages can refer to the source, e.g. home.ts, or to the bundled home.js
+                let filenames = [
+                    filename_from_path(&path),
+                    filename_from_path(&path.with_extension("js")),
+                ];
+                for filename in filenames {
+                    // This might cause problems some day by replacing some text also, but whatever
+                    let from1 = format!(r#"{}""#, filename);
+                    let to1 = format!(r#"{}""#, value);
+                    let from2 = format!(r#"{}>"#, filename);
+                    let to2 = format!(r#"{}>"#, value);
+                    let from3 = format!(r#"{}/>"#, filename);
+                    let to3 = format!(r#"{}/>"#, value);
+                    page_str = page_str.replace(&from1, &to1);
+                    page_str = page_str.replace(&from2, &to2);
+                    page_str = page_str.replace(&from3, &to3);
+                }
+            }
+            for (key, value) in css_hashes.iter() {
+                let path = Path::new(&key).to_path_buf();
+                let filename = filename_from_path(&path);
+                // This might cause problems some day by replacing some text also, but whatever
+                let from1 = format!(r#"{}""#, filename);
+                let to1 = format!(r#"{}""#, value);
+                let from2 = format!(r#"{}>"#, filename);
+                let to2 = format!(r#"{}>"#, value);
+                let from3 = format!(r#"{}/>"#, filename);
+                let to3 = format!(r#"{}/>"#, value);
+                page_str = page_str.replace(&from1, &to1);
+                page_str = page_str.replace(&from2, &to2);
+                page_str = page_str.replace(&from3, &to3);
+            }
+            for (key, value) in media_hashes.iter() {
+                let path = Path::new(&key).to_path_buf();
+                let filename = filename_from_path(&path);
+                // This might cause problems some day by replacing some text also, but whatever
+                let from1 = format!(r#"{}""#, filename);
+                let to1 = format!(r#"{}""#, value);
+                let from2 = format!(r#"{}>"#, filename);
+                let to2 = format!(r#"{}>"#, value);
+                let from3 = format!(r#"{}/>"#, filename);
+                let to3 = format!(r#"{}/>"#, value);
+                page_str = page_str.replace(&from1, &to1);
+                page_str = page_str.replace(&from2, &to2);
+                page_str = page_str.replace(&from3, &to3);
+            }
+            page_str = img_attributes(&page_str, &image_sizes, &config.images);
+            if config.images.responsive {
+                page_str = responsive_img_tags(&page_str, &responsive_images, &config.images.sizes);
+            }
+            if config.auto_inject.enabled {
+                // Only top level files, e.g. src/scripts/about.ts for the about page
+                let script = SCRIPT_EXTENSIONS
+                    .iter()
+                    .find_map(|ext| js_hashes.get(&format!("src/scripts/{page_name}.{ext}")));
+                let style = css_hashes.get(&format!("src/styles/{page_name}.css"));
+                page_str = inject_page_assets(
+                    &page_str,
+                    script.map(String::as_str),
+                    style.map(String::as_str),
+                    &config.auto_inject.script_loading,
+                );
+            }
+            if !split_entries.is_empty() {
+                page_str = module_scripts(&page_str, &split_entries);
+            }
+            if !preload_urls.is_empty() {
+                page_str = add_preloads(&page_str, &preload_urls, !split_entries.is_empty());
+            }
+            if !dev && config.html.critical_css {
+                // Only built stylesheets, not ones from other sites
+                page_str = inline_critical_css(&page_str, |href| {
+                    let stylesheet = built_styles.get(href)?;
+                    Some(critical_css(stylesheet, &page_str))
+                });
+            }
+            if config.html.integrity {
+                page_str = add_integrity(&page_str, |url| {
+                    // Local files only, query and hash are not part of the path
+                    if !url.starts_with('/') || url.starts_with("//") {
+                        return None;
                     }
-                    path = path.join(format!("{page_name}.html"));
-
-                    fs::write(path, page_str).unwrap();
+                    let path = url[1..].split(['?', '#']).next().unwrap();
+                    let path = dist_path.join(path);
+                    integrities
+                        .entry(url.to_string())
+                        .or_insert_with(|| path.is_file().then(|| integrity(&path)))
+                        .clone()
+                });
+            }
+            if !dev && config.html.minify {
+                page_str = minify_page(&page_str);
+            }
+            let path = page_path(&intl, &key, page_name);
+            // Last so the hashes are of the final inline scripts and styles
+            if config.csp.enabled {
+                let policy = content_security_policy(&page_str, &config.csp, dev);
+                if config.csp.output == CspOutput::Headers && !dev {
+                    csp_headers.insert(path.clone(), policy);
+                } else {
+                    let meta = format!(
+                        "<meta http-equiv=\"Content-Security-Policy\" content=\"{policy}\">"
+                    );
+                    page_str = add_to_head(&page_str, &meta).unwrap_or_else(|| {
+                        panic!("dist/{path} has no <head> for the csp meta tag")
+                    });
                 }
             }
-            Err(_) => panic!("failed to read page"),
+            // The default language is already in the root
+            let in_root = key == "default" || intl.default_lang.as_ref() == Some(&key);
+            if page_name == "index" && !in_root {
+                language_roots.push((key.clone(), path.clone()));
+            }
+            if let Some(other) = page_paths.insert(path.clone(), page_name) {
+                panic!("{page_name} and {other} are both written to dist/{path}, check the slugs");
+            }
+            let path = dist_path.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, page_str).unwrap();
         }
     }
+    if !dev {
+        let mut pages = page_paths.keys().collect::<Vec<&String>>();
+        pages.sort();
+        write_deploy_files(&pages, &csp_headers, &config.deploy, &language_roots);
+    }
+    if let Some(site_url) = &config.site_url {
+        fs::write("dist/sitemap.xml", sitemap(&intl, &page_names, site_url)).unwrap();
+        println!("Wrote dist/sitemap.xml");
+    }
+    write_asset_manifest([&js_hashes, &css_hashes, &media_hashes], &source_maps);
+}
+
+/* dist/asset-manifest.json has the hashed path of every asset by its source path and the source maps,
+e.g. for uploading hidden maps to an error tracker */
+fn write_asset_manifest(hashes: [&HashMap<String, String>; 3], source_maps: &[PathBuf]) {
+    let dist_relative = |path: &Path| {
+        path.strip_prefix("dist")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    };
+    let mut assets = BTreeMap::new();
+    for hashes in hashes {
+        for (src, hashed) in hashes.iter() {
+            let dist_path = Path::new(&src_path_to_dist_path(src)).with_file_name(hashed);
+            assets.insert(src.to_string(), dist_relative(&dist_path));
+        }
+    }
+    let source_maps = source_maps
+        .iter()
+        .map(|path| dist_relative(path))
+        .collect::<Vec<String>>();
+    let manifest = serde_json::json!({ "assets": assets, "sourceMaps": source_maps });
+    fs::write(
+        "dist/asset-manifest.json",
+        serde_json::to_string_pretty(&manifest).unwrap() + "\n",
+    )
+    .unwrap();
 }
 
 // static mut processing: bool = false;
@@ -420,16 +680,13 @@ fn watch(refresher_token: Arc<Mutex<i32>>) {
         .unwrap();
 
     build(true);
-    start_dev_server();
+    let mut dev_server = start_dev_server();
     let processing = Arc::new(Mutex::new(false));
     for res in rx {
         match res {
-            Ok(event) => unsafe {
+            Ok(_) => {
                 let processing_handle = processing.clone();
-                let mut curr_processing = false;
-                {
-                    curr_processing = *processing_handle.lock().unwrap();
-                }
+                let curr_processing = *processing_handle.lock().unwrap();
                 if !curr_processing {
                     let processing_handle_thread = processing.clone();
                     {
@@ -443,10 +700,11 @@ fn watch(refresher_token: Arc<Mutex<i32>>) {
                         *processing_handle_thread.lock().unwrap() = false;
                     });
                 }
-            },
+            }
             Err(e) => panic!("watch error: {:?}", e),
         }
     }
+    dev_server.wait().unwrap();
 }
 
 fn start_dev_server() -> Child {
@@ -481,6 +739,15 @@ fn main() {
         Commands::Build => {
             build(false);
         }
+        Commands::Translations { command } => {
+            let config = load_config();
+            match command {
+                TranslationsCommand::Export { format, output } => {
+                    export_translations(&config.intl, format, output.as_deref())
+                }
+                TranslationsCommand::Import { files } => import_translations(&config.intl, files),
+            }
+        }
         Commands::New => {
             let input: String = Input::with_theme(&ColorfulTheme::default())
                 .with_prompt("Output directory")
@@ -505,7 +772,7 @@ fn main() {
                 "pages/about.html",
                 "media/sample.png",
                 "intl/en.json",
-                "intl/fi_default.json",
+                "intl/fi.json",
             ];
             for f in files {
                 let splitted = f.split("/");
@@ -539,6 +806,11 @@ fn main() {
                 asset_to_string("tailwind.config.js"),
             )
             .unwrap();
+            fs::write(
+                Path::new(&input).join("mpa.json"),
+                asset_to_string("mpa.json"),
+            )
+            .unwrap();
             let node_version_file = File::create(Path::new(&input).join(".node-version")).unwrap();
             let mut node_version_cmd = Command::new("node")
                 .args(vec!["--version"])
@@ -546,7 +818,11 @@ fn main() {
                 .spawn()
                 .unwrap();
             node_version_cmd.wait().unwrap();
-            fs::write(Path::new(&input).join(".gitignore"), "node_modules\ndist").unwrap();
+            fs::write(
+                Path::new(&input).join(".gitignore"),
+                "node_modules\ndist\n.mpa-cache",
+            )
+            .unwrap();
         }
     }
 }
@@ -557,15 +833,19 @@ fn asset_to_string(path: &str) -> String {
     String::from(s)
 }
 
-fn run_command_and_wait(prog: &str, args: Option<Vec<&str>>, directory: Option<&String>) {
+fn run_command_and_wait(
+    prog: &str,
+    args: Option<Vec<&str>>,
+    directory: Option<&String>,
+) -> ExitStatus {
     let mut cmd = Command::new(prog);
-    if args.is_some() {
-        cmd.args(args.unwrap());
+    if let Some(args) = args {
+        cmd.args(args);
     }
-    if directory.is_some() {
-        cmd.current_dir(directory.unwrap());
+    if let Some(directory) = directory {
+        cmd.current_dir(directory);
     }
 
     let child = cmd.spawn().unwrap();
-    child.wait_with_output().unwrap();
+    child.wait_with_output().unwrap().status
 }
@@ -0,0 +1,469 @@
+use std::collections::HashMap;
+
+use icu::plurals::{PluralCategory, PluralOperands, PluralRuleType, PluralRules};
+use serde_json::Value;
+
+use crate::format::{data_locale, format_currency, format_date, format_number};
+
+/* Subset of ICU MessageFormat: {arg}, {arg, number}, {arg, date}, {arg, plural, ...}, {arg, selectordinal, ...}
+and {arg, select, ...}
+Plural cases are exact values (=0) or CLDR categories (zero, one, two, few, many, other), # is the number
+Number styles are integer and currency/EUR, date styles are short, medium, long and full */
+enum Part {
+    Text(String),
+    Arg(String),
+    Hash,
+    Number {
+        arg: String,
+        style: Option<String>,
+    },
+    Date {
+        arg: String,
+        style: Option<String>,
+    },
+    Plural {
+        arg: String,
+        ordinal: bool,
+        offset: f64,
+        cases: Vec<(String, Vec<Part>)>,
+    },
+    Select {
+        arg: String,
+        cases: Vec<(String, Vec<Part>)>,
+    },
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(found) if found == c => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(found) => Err(format!(
+                "expected '{c}' but found '{found}' at {}",
+                self.pos
+            )),
+            None => Err(format!("expected '{c}' but message ended")),
+        }
+    }
+
+    fn word(&mut self) -> String {
+        self.skip_whitespace();
+        let mut word = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() || c == ',' || c == '{' || c == '}' {
+                break;
+            }
+            word.push(c);
+            self.pos += 1;
+        }
+        word
+    }
+
+    fn message(&mut self, in_plural: bool) -> Result<Vec<Part>, String> {
+        let mut parts = vec![];
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            match c {
+                '}' => break,
+                '{' => {
+                    self.pos += 1;
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(self.argument(in_plural)?);
+                }
+                '#' if in_plural => {
+                    self.pos += 1;
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Hash);
+                }
+                // '' is a literal apostrophe and an apostrophe before a special character quotes until the next one
+                '\'' => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some('\'') => {
+                            text.push('\'');
+                            self.pos += 1;
+                        }
+                        Some('{') | Some('}') | Some('#') => {
+                            while let Some(c) = self.peek() {
+                                self.pos += 1;
+                                if c == '\'' {
+                                    if self.peek() != Some('\'') {
+                                        break;
+                                    }
+                                    self.pos += 1;
+                                }
+                                text.push(c);
+                            }
+                        }
+                        _ => text.push('\''),
+                    }
+                }
+                _ => {
+                    text.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(parts)
+    }
+
+    fn cases(&mut self, in_plural: bool) -> Result<Vec<(String, Vec<Part>)>, String> {
+        let mut cases = vec![];
+        loop {
+            self.skip_whitespace();
+            if self.peek() == Some('}') || self.peek().is_none() {
+                break;
+            }
+            let selector = self.word();
+            self.expect('{')?;
+            let message = self.message(in_plural)?;
+            self.expect('}')?;
+            cases.push((selector, message));
+        }
+        if !cases.iter().any(|(selector, _)| selector == "other") {
+            return Err("missing the other case".to_string());
+        }
+        Ok(cases)
+    }
+
+    fn argument(&mut self, in_plural: bool) -> Result<Part, String> {
+        let arg = self.word();
+        if arg.is_empty() {
+            return Err(format!("missing argument name at {}", self.pos));
+        }
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Part::Arg(arg));
+        }
+        self.expect(',')?;
+        let arg_type = self.word();
+        let part = match arg_type.as_str() {
+            "plural" | "selectordinal" => {
+                self.expect(',')?;
+                self.skip_whitespace();
+                let mut offset = 0.0;
+                let rest = self.chars[self.pos..].iter().collect::<String>();
+                if rest.starts_with("offset:") {
+                    self.pos += "offset:".len();
+                    let value = self.word();
+                    offset = value
+                        .parse()
+                        .map_err(|_| format!("invalid offset {value}"))?;
+                }
+                Part::Plural {
+                    arg,
+                    ordinal: arg_type == "selectordinal",
+                    offset,
+                    cases: self.cases(true)?,
+                }
+            }
+            "number" | "date" => {
+                self.skip_whitespace();
+                let style = if self.peek() == Some(',') {
+                    self.pos += 1;
+                    Some(self.word().trim_start_matches("::").to_string())
+                } else {
+                    None
+                };
+                if arg_type == "number" {
+                    Part::Number { arg, style }
+                } else {
+                    Part::Date { arg, style }
+                }
+            }
+            "select" => {
+                self.expect(',')?;
+                Part::Select {
+                    arg,
+                    cases: self.cases(in_plural)?,
+                }
+            }
+            _ => return Err(format!("unsupported argument type {arg_type}")),
+        };
+        self.expect('}')?;
+        Ok(part)
+    }
+}
+
+fn parse(message: &str) -> Result<Vec<Part>, String> {
+    let mut parser = Parser {
+        chars: message.chars().collect(),
+        pos: 0,
+    };
+    let parts = parser.message(false)?;
+    if parser.pos < parser.chars.len() {
+        return Err(format!("unexpected '}}' at {}", parser.pos));
+    }
+    Ok(parts)
+}
+
+fn number_to_string(n: f64) -> String {
+    if n.fract() == 0.0 {
+        format!("{}", n as i64)
+    } else {
+        n.to_string()
+    }
+}
+
+fn arg_number(arg: &str, args: &HashMap<String, Value>) -> Result<f64, String> {
+    match args.get(arg) {
+        Some(Value::Number(n)) => Ok(n.as_f64().unwrap()),
+        Some(Value::String(s)) => s
+            .parse()
+            .map_err(|_| format!("argument {arg} is not a number")),
+        Some(_) => Err(format!("argument {arg} is not a number")),
+        None => Err(format!("missing argument {arg}")),
+    }
+}
+
+fn plural_category(lang: &str, ordinal: bool, n: f64) -> &'static str {
+    let rule_type = if ordinal {
+        PluralRuleType::Ordinal
+    } else {
+        PluralRuleType::Cardinal
+    };
+    // Unknown languages use the root rules, which only have the other category
+    let category = PluralRules::try_new(&data_locale(lang), rule_type)
+        .ok()
+        .zip(number_to_string(n).parse::<PluralOperands>().ok())
+        .map(|(rules, operands)| rules.category_for(operands));
+    match category {
+        Some(PluralCategory::Zero) => "zero",
+        Some(PluralCategory::One) => "one",
+        Some(PluralCategory::Two) => "two",
+        Some(PluralCategory::Few) => "few",
+        Some(PluralCategory::Many) => "many",
+        _ => "other",
+    }
+}
+
+fn format_parts(
+    parts: &[Part],
+    lang: &str,
+    args: &HashMap<String, Value>,
+    number: Option<f64>,
+    out: &mut String,
+) -> Result<(), String> {
+    for part in parts {
+        match part {
+            Part::Text(text) => out.push_str(text),
+            Part::Hash => out.push_str(&format_number(lang, number.unwrap(), None)?),
+            Part::Number { arg, style } => {
+                let n = arg_number(arg, args)?;
+                let formatted = match style.as_deref() {
+                    None => format_number(lang, n, None)?,
+                    Some("integer") => format_number(lang, n, Some(0))?,
+                    Some(style) => match style.strip_prefix("currency/") {
+                        Some(currency) => format_currency(lang, n, currency)?,
+                        None => return Err(format!("unknown number style {style}")),
+                    },
+                };
+                out.push_str(&formatted);
+            }
+            Part::Date { arg, style } => {
+                let value = match args.get(arg) {
+                    Some(Value::String(s)) => s,
+                    Some(_) => return Err(format!("argument {arg} is not a date")),
+                    None => return Err(format!("missing argument {arg}")),
+                };
+                let style = style.as_deref().unwrap_or("medium");
+                out.push_str(&format_date(lang, value, style, None)?);
+            }
+            Part::Arg(arg) => match args.get(arg) {
+                Some(Value::String(s)) => out.push_str(s),
+                Some(Value::Number(n)) => {
+                    out.push_str(&format_number(lang, n.as_f64().unwrap(), None)?)
+                }
+                Some(value) => out.push_str(&value.to_string()),
+                None => return Err(format!("missing argument {arg}")),
+            },
+            Part::Plural {
+                arg,
+                ordinal,
+                offset,
+                cases,
+            } => {
+                let value = arg_number(arg, args)?;
+                let exact = format!("={}", number_to_string(value));
+                let category = plural_category(lang, *ordinal, value - offset);
+                let case = cases
+                    .iter()
+                    .find(|(selector, _)| *selector == exact)
+                    .or_else(|| cases.iter().find(|(selector, _)| selector == category))
+                    .or_else(|| cases.iter().find(|(selector, _)| selector == "other"))
+                    .unwrap();
+                format_parts(&case.1, lang, args, Some(value - offset), out)?;
+            }
+            Part::Select { arg, cases } => {
+                let value = match args.get(arg) {
+                    Some(Value::String(s)) => s.to_string(),
+                    Some(value) => value.to_string(),
+                    None => return Err(format!("missing argument {arg}")),
+                };
+                let case = cases
+                    .iter()
+                    .find(|(selector, _)| *selector == value)
+                    .or_else(|| cases.iter().find(|(selector, _)| selector == "other"))
+                    .unwrap();
+                format_parts(&case.1, lang, args, number, out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn format_message(
+    message: &str,
+    lang: &str,
+    args: &HashMap<String, Value>,
+) -> Result<String, String> {
+    let parts = parse(message)?;
+    let mut out = String::new();
+    format_parts(&parts, lang, args, None, &mut out)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(message: &str, lang: &str, args: &[(&str, Value)]) -> Result<String, String> {
+        let args = args
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect();
+        format_message(message, lang, &args)
+    }
+
+    #[test]
+    fn plural_categories_come_from_cldr() {
+        let message =
+            "{n, plural, one {# tuote} few {# produkty} many {# produktów} other {# items}}";
+        assert_eq!(
+            format(message, "fi", &[("n", 1.into())]).unwrap(),
+            "1 tuote"
+        );
+        assert_eq!(
+            format(message, "pl", &[("n", 3.into())]).unwrap(),
+            "3 produkty"
+        );
+        assert_eq!(
+            format(message, "pl", &[("n", 5.into())]).unwrap(),
+            "5 produktów"
+        );
+        // Decimals are never one in English
+        assert_eq!(
+            format(message, "en", &[("n", 1.5.into())]).unwrap(),
+            "1.5 items"
+        );
+        // Unknown languages only have other
+        assert_eq!(
+            format(message, "xx", &[("n", 1.into())]).unwrap(),
+            "1 items"
+        );
+    }
+
+    #[test]
+    fn exact_values_and_offset() {
+        let message = "{n, plural, offset:1 =0 {nobody} =1 {you} one {you and # other} other {you and # others}}";
+        assert_eq!(format(message, "en", &[("n", 0.into())]).unwrap(), "nobody");
+        assert_eq!(format(message, "en", &[("n", 1.into())]).unwrap(), "you");
+        assert_eq!(
+            format(message, "en", &[("n", 2.into())]).unwrap(),
+            "you and 1 other"
+        );
+        assert_eq!(
+            format(message, "en", &[("n", 3.into())]).unwrap(),
+            "you and 2 others"
+        );
+    }
+
+    #[test]
+    fn selectordinal_and_select() {
+        let message = "{n, selectordinal, one {#st} two {#nd} few {#rd} other {#th}}";
+        assert_eq!(format(message, "en", &[("n", 22.into())]).unwrap(), "22nd");
+        assert_eq!(format(message, "en", &[("n", 13.into())]).unwrap(), "13th");
+        let message = "{gender, select, female {her} other {their}}";
+        assert_eq!(
+            format(message, "en", &[("gender", "female".into())]).unwrap(),
+            "her"
+        );
+        assert_eq!(
+            format(message, "en", &[("gender", "x".into())]).unwrap(),
+            "their"
+        );
+    }
+
+    #[test]
+    fn parts_of_a_message() {
+        let parts = parse("Hi {name}, {n, number, ::integer} on {d, date, long}!").unwrap();
+        assert!(matches!(
+            parts.as_slice(),
+            [
+                Part::Text(hi),
+                Part::Arg(name),
+                Part::Text(_),
+                Part::Number { style: Some(number_style), .. },
+                Part::Text(_),
+                Part::Date { style: Some(date_style), .. },
+                Part::Text(end),
+            ] if hi == "Hi " && name == "name" && number_style == "integer" && date_style == "long" && end == "!"
+        ));
+        let parts = parse("{n, plural, offset:1 =0 {none} other {# more}}").unwrap();
+        let [Part::Plural {
+            offset,
+            cases,
+            ordinal: false,
+            ..
+        }] = parts.as_slice()
+        else {
+            panic!("expected a plural");
+        };
+        assert_eq!(*offset, 1.0);
+        assert_eq!(cases[0].0, "=0");
+        assert!(matches!(cases[1].1.as_slice(), [Part::Hash, Part::Text(more)] if more == " more"));
+    }
+
+    #[test]
+    fn apostrophes_quote_special_characters() {
+        let parts = parse("It''s '{literal}' and '#'").unwrap();
+        assert!(matches!(parts.as_slice(), [Part::Text(text)] if text == "It's {literal} and #"));
+        assert!(parse("{n, unknown}").is_err());
+        assert!(parse("{}").is_err());
+        assert!(parse("a}").is_err());
+    }
+
+    #[test]
+    fn invalid_messages_are_errors() {
+        assert!(parse("{n, plural, one {x}").is_err());
+        assert!(parse("{n, plural, one {x}}").is_err());
+        assert!(format("{n, plural, other {#}}", "en", &[]).is_err());
+        assert!(format("{n, plural, other {#}}", "en", &[("n", "a".into())]).is_err());
+    }
+}
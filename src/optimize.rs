@@ -0,0 +1,443 @@
+use std::{
+    ffi::c_void,
+    fs, mem,
+    os::raw::{c_int, c_ulong},
+    panic,
+    path::Path,
+    ptr, slice,
+};
+
+use mozjpeg_sys::{
+    jpeg_common_struct, jpeg_compress_struct, jpeg_copy_critical_parameters, jpeg_create_compress,
+    jpeg_create_decompress, jpeg_decompress_struct, jpeg_destroy_compress, jpeg_destroy_decompress,
+    jpeg_error_mgr, jpeg_finish_compress, jpeg_finish_decompress, jpeg_mem_dest, jpeg_mem_src,
+    jpeg_read_coefficients, jpeg_read_header, jpeg_save_markers, jpeg_simple_progression,
+    jpeg_std_error, jpeg_write_coefficients, jpeg_write_marker,
+};
+use oxipng::{Options, StripChunks};
+use quick_xml::{
+    escape::escape,
+    events::{BytesStart, BytesText, Event},
+    Reader, Writer,
+};
+
+use crate::images::{digest, CACHE_DIR};
+
+extern "C" {
+    // libjpeg allocates the output with malloc
+    fn free(ptr: *mut c_void);
+}
+
+// APP1 holds Exif and XMP, APP2 the icc color profile among others
+const JPEG_APP1: c_int = 0xE1;
+const JPEG_APP2: c_int = 0xE2;
+
+// The only Exif tag that is kept, it tells how the image is rotated
+const EXIF_ORIENTATION: u16 = 0x0112;
+
+// Orientation from 2 to 8 in an Exif APP1 segment, 1 is the default and needs no Exif
+fn exif_orientation(data: &[u8]) -> Option<u16> {
+    let tiff = data.strip_prefix(b"Exif\0\0")?;
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |i: usize| {
+        let bytes = tiff.get(i..i + 2)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let u32_at = |i: usize| {
+        let bytes = tiff.get(i..i + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+    // Entries of the first IFD are 12 bytes: tag, type, count and the value
+    let ifd = u32_at(4)? as usize;
+    let entries = u16_at(ifd)? as usize;
+    let entry = (0..entries)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| u16_at(entry) == Some(EXIF_ORIENTATION))?;
+    u16_at(entry + 8).filter(|orientation| (2..=8).contains(orientation))
+}
+
+// Exif APP1 segment with only the orientation
+fn orientation_exif(orientation: u16) -> Vec<u8> {
+    let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+    exif.extend(EXIF_ORIENTATION.to_be_bytes());
+    // A single short, padded to 4 bytes, and no next IFD
+    exif.extend([0, 3, 0, 0, 0, 1]);
+    exif.extend(orientation.to_be_bytes());
+    exif.extend([0; 6]);
+    exif
+}
+
+// libjpeg must not return from errors, unwinding without the panic hook keeps the output clean
+unsafe extern "C-unwind" fn jpeg_error_exit(_: &mut jpeg_common_struct) {
+    panic::resume_unwind(Box::new("invalid jpeg"));
+}
+
+/* libjpeg objects are destroyed and the output freed when they are dropped, also when an error
+unwinds. They are boxed because libjpeg keeps pointers to them and to their error managers */
+struct Decompress(Box<jpeg_decompress_struct>, Box<jpeg_error_mgr>);
+
+impl Decompress {
+    unsafe fn new() -> Self {
+        let mut decompress = Decompress(Box::new(mem::zeroed()), Box::new(mem::zeroed()));
+        decompress.0.common.err = jpeg_std_error(&mut decompress.1);
+        decompress.1.error_exit = Some(jpeg_error_exit);
+        jpeg_create_decompress(&mut *decompress.0);
+        decompress
+    }
+}
+
+impl Drop for Decompress {
+    fn drop(&mut self) {
+        unsafe { jpeg_destroy_decompress(&mut self.0) }
+    }
+}
+
+struct Compress(Box<jpeg_compress_struct>, Box<jpeg_error_mgr>);
+
+impl Compress {
+    unsafe fn new() -> Self {
+        let mut compress = Compress(Box::new(mem::zeroed()), Box::new(mem::zeroed()));
+        compress.0.common.err = jpeg_std_error(&mut compress.1);
+        compress.1.error_exit = Some(jpeg_error_exit);
+        jpeg_create_compress(&mut *compress.0);
+        compress
+    }
+}
+
+impl Drop for Compress {
+    fn drop(&mut self) {
+        unsafe { jpeg_destroy_compress(&mut self.0) }
+    }
+}
+
+// Output of jpeg_mem_dest
+struct Output {
+    ptr: *mut u8,
+    len: c_ulong,
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            unsafe { free(self.ptr as *mut c_void) }
+        }
+    }
+}
+
+/* Lossless like jpegtran -optimize -progressive -copy icc: the coefficients are written again with
+optimized Huffman tables. Metadata like Exif, XMP and comments is dropped, only the icc profile and
+the Exif orientation are kept so the image looks the same */
+fn optimize_jpeg(bytes: &[u8]) -> Option<Vec<u8>> {
+    panic::catch_unwind(|| unsafe {
+        // Dropped in reverse order, the output is written from the coefficients of src
+        let mut src = Decompress::new();
+        let mut output = Box::new(Output {
+            ptr: ptr::null_mut(),
+            len: 0,
+        });
+        let mut dst = Compress::new();
+
+        jpeg_mem_src(&mut src.0, bytes.as_ptr(), bytes.len() as c_ulong);
+        jpeg_save_markers(&mut src.0, JPEG_APP1, 0xFFFF);
+        jpeg_save_markers(&mut src.0, JPEG_APP2, 0xFFFF);
+        jpeg_read_header(&mut src.0, 1);
+        let coefficients = jpeg_read_coefficients(&mut src.0);
+
+        jpeg_mem_dest(&mut dst.0, &mut output.ptr, &mut output.len);
+        jpeg_copy_critical_parameters(&src.0, &mut dst.0);
+        dst.0.optimize_coding = 1;
+        jpeg_simple_progression(&mut dst.0);
+        jpeg_write_coefficients(&mut dst.0, coefficients);
+        let mut marker = src.0.marker_list;
+        while !marker.is_null() {
+            let m = &*marker;
+            let data = slice::from_raw_parts(m.data, m.data_length as usize);
+            if m.marker as c_int == JPEG_APP2 && data.starts_with(b"ICC_PROFILE\0") {
+                jpeg_write_marker(&mut dst.0, JPEG_APP2, m.data, m.data_length);
+            } else if let Some(orientation) = exif_orientation(data) {
+                let exif = orientation_exif(orientation);
+                jpeg_write_marker(&mut dst.0, JPEG_APP1, exif.as_ptr(), exif.len() as u32);
+            }
+            marker = m.next;
+        }
+        jpeg_finish_compress(&mut dst.0);
+        jpeg_finish_decompress(&mut src.0);
+        slice::from_raw_parts(output.ptr, output.len as usize).to_vec()
+    })
+    .ok()
+}
+
+fn optimize_png(bytes: &[u8]) -> Option<Vec<u8>> {
+    let options = Options {
+        // Color profiles and other chunks that change how the image looks are kept
+        strip: StripChunks::Safe,
+        ..Options::from_preset(2)
+    };
+    oxipng::optimize_from_memory(bytes, &options).ok()
+}
+
+// Elements and attributes of editors, e.g. inkscape:label, that browsers don't use
+fn is_editor_data(name: &[u8]) -> bool {
+    ["sodipodi:", "inkscape:", "xmlns:sodipodi", "xmlns:inkscape"]
+        .iter()
+        .any(|prefix| name.starts_with(prefix.as_bytes()))
+}
+
+// Entities, if any, are expanded in the values
+fn without_editor_attributes(e: &BytesStart, entities: &[(String, String)]) -> BytesStart<'static> {
+    let name = String::from_utf8_lossy(e.name().as_ref()).to_string();
+    let mut start = BytesStart::new(name);
+    for attribute in e.attributes().flatten() {
+        if is_editor_data(attribute.key.as_ref()) {
+            continue;
+        }
+        if entities.is_empty() {
+            start.push_attribute(attribute);
+        } else {
+            let value = expand_entities(&attribute.value, entities);
+            start.push_attribute((attribute.key.as_ref(), value.as_bytes()));
+        }
+    }
+    start
+}
+
+/* Internal entities of the doctype as (reference, escaped value), e.g. Illustrator declares
+<!ENTITY ns_svg "http://www.w3.org/2000/svg"> for xmlns="&ns_svg;". External and parameter entities
+and values with markup or references are errors */
+fn internal_entities(doctype: &[u8]) -> Result<Vec<(String, String)>, String> {
+    let doctype = String::from_utf8_lossy(doctype);
+    let mut entities = vec![];
+    for declaration in doctype.split("<!ENTITY").skip(1) {
+        let (name, rest) = declaration
+            .trim_start()
+            .split_once(|c: char| c.is_ascii_whitespace())
+            .unwrap_or_default();
+        let rest = rest.trim_start();
+        let value = ['"', '\'']
+            .into_iter()
+            .find_map(|quote| rest.strip_prefix(quote)?.split_once(quote))
+            .map(|(value, _)| value)
+            .filter(|value| !value.contains(['<', '&', '%']))
+            .ok_or(format!("has an entity {name} that can't be inlined"))?;
+        entities.push((format!("&{name};"), escape(value).into_owned()));
+    }
+    Ok(entities)
+}
+
+fn expand_entities(raw: &[u8], entities: &[(String, String)]) -> String {
+    let mut expanded = String::from_utf8_lossy(raw).into_owned();
+    for (reference, value) in entities {
+        expanded = expanded.replace(reference, value);
+    }
+    expanded
+}
+
+const INVALID_SVG: &str = "is not a valid svg";
+
+/* Removes comments, the xml declaration, <metadata>, editor data and white space between tags.
+White space in <text> is kept and nothing that changes how the svg looks is touched. The doctype stays,
+Illustrator declares entities in it that attributes refer to */
+pub fn minify_svg(bytes: &[u8]) -> Option<Vec<u8>> {
+    minify(bytes, false).ok()
+}
+
+/* Minified svg for inlining into html, which has no doctypes. The doctype is dropped and the entities
+it declares are expanded */
+pub fn minify_svg_for_html(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    minify(bytes, true)
+}
+
+fn minify(bytes: &[u8], for_html: bool) -> Result<Vec<u8>, String> {
+    let mut reader = Reader::from_reader(bytes);
+    let mut writer = Writer::new(vec![]);
+    let mut buf = vec![];
+    let mut entities = vec![];
+    // Depth inside removed elements and inside <text>
+    let mut skip_depth = 0;
+    let mut text_depth = 0;
+    loop {
+        let event = reader.read_event_into(&mut buf).map_err(|_| INVALID_SVG)?;
+        let removed = |name: &[u8]| name == b"metadata" || is_editor_data(name);
+        match event {
+            Event::Eof => break,
+            Event::Start(e) if skip_depth > 0 || removed(e.name().as_ref()) => {
+                skip_depth += 1;
+            }
+            Event::End(_) if skip_depth > 0 => skip_depth -= 1,
+            _ if skip_depth > 0 => {}
+            Event::Decl(_) | Event::Comment(_) | Event::PI(_) => {}
+            Event::DocType(e) if for_html => entities = internal_entities(&e)?,
+            Event::Empty(e) if removed(e.name().as_ref()) => {}
+            Event::Start(e) => {
+                if matches!(e.name().as_ref(), b"text" | b"style" | b"script") || text_depth > 0 {
+                    text_depth += 1;
+                }
+                writer
+                    .write_event(Event::Start(without_editor_attributes(&e, &entities)))
+                    .map_err(|_| INVALID_SVG)?;
+            }
+            Event::Empty(e) => writer
+                .write_event(Event::Empty(without_editor_attributes(&e, &entities)))
+                .map_err(|_| INVALID_SVG)?,
+            Event::End(e) => {
+                text_depth = (text_depth - 1).max(0);
+                writer.write_event(Event::End(e)).map_err(|_| INVALID_SVG)?;
+            }
+            Event::Text(e) if text_depth == 0 && e.iter().all(u8::is_ascii_whitespace) => {}
+            Event::Text(e) if !entities.is_empty() => {
+                let text = BytesText::from_escaped(expand_entities(&e, &entities));
+                writer
+                    .write_event(Event::Text(text))
+                    .map_err(|_| INVALID_SVG)?;
+            }
+            event => writer.write_event(event).map_err(|_| INVALID_SVG)?,
+        }
+        buf.clear();
+    }
+    Ok(writer.into_inner())
+}
+
+/* Optimized content of a media file, or the file as it is when it can't be made smaller.
+Results are cached in .mpa-cache by the digest of the file, so only new and changed files are optimized */
+pub fn optimize_media(path: &Path) -> Vec<u8> {
+    let bytes = fs::read(path).unwrap();
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    let optimize: fn(&[u8]) -> Option<Vec<u8>> = match extension.as_str() {
+        "png" => optimize_png,
+        "jpg" | "jpeg" => optimize_jpeg,
+        "svg" => minify_svg,
+        _ => return bytes,
+    };
+    let cache_path = Path::new(CACHE_DIR).join(format!("optimized/{}.{extension}", digest(&bytes)));
+    if let Ok(cached) = fs::read(&cache_path) {
+        return cached;
+    }
+    let optimized = match optimize(&bytes) {
+        Some(optimized) if optimized.len() < bytes.len() => optimized,
+        Some(_) => bytes,
+        None => {
+            println!(
+                "Could not optimize {}, it is copied as it is",
+                path.to_str().unwrap()
+            );
+            bytes
+        }
+    };
+    fs::create_dir_all(cache_path.parent().unwrap()).unwrap();
+    fs::write(&cache_path, &optimized).unwrap();
+    optimized
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{
+        codecs::jpeg::{JpegDecoder, JpegEncoder},
+        metadata::Orientation,
+        ExtendedColorType, ImageDecoder, ImageEncoder,
+    };
+
+    use super::*;
+
+    /* Little endian Exif with the orientation, 6 is rotated 90 degrees clockwise, and a GPS IFD
+    at offset 38 with the latitude reference */
+    const EXIF: [u8; 56] = [
+        b'I', b'I', 42, 0, 8, 0, 0, 0, 2, 0, 0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0, 0x25, 0x88,
+        4, 0, 1, 0, 0, 0, 38, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 0, 2, 0, 2, 0, 0, 0, b'N', 0, 0, 0, 0,
+        0, 0, 0,
+    ];
+
+    const XMP: &[u8] =
+        b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta><tiff:Make>Camera</tiff:Make></x:xmpmeta>";
+
+    fn jpeg_with_exif() -> Vec<u8> {
+        let pixels = (0..16 * 8 * 3).map(|i| (i * 7) as u8).collect::<Vec<u8>>();
+        let mut bytes = vec![];
+        let mut encoder = JpegEncoder::new_with_quality(&mut bytes, 90);
+        encoder.set_exif_metadata(EXIF.to_vec()).unwrap();
+        encoder
+            .write_image(&pixels, 16, 8, ExtendedColorType::Rgb8)
+            .unwrap();
+        // XMP segment right after the start of image
+        let mut xmp = vec![0xFF, JPEG_APP1 as u8];
+        xmp.extend((XMP.len() as u16 + 2).to_be_bytes());
+        xmp.extend(XMP);
+        bytes.splice(2..2, xmp);
+        bytes
+    }
+
+    #[test]
+    fn jpeg_keeps_only_the_exif_orientation() {
+        let original = jpeg_with_exif();
+        assert!(original.windows(EXIF.len()).any(|bytes| bytes == EXIF));
+        let optimized = optimize_jpeg(&original).unwrap();
+        let mut decoder = JpegDecoder::new(Cursor::new(&optimized)).unwrap();
+        assert_eq!(decoder.dimensions(), (16, 8));
+        assert_eq!(decoder.orientation().unwrap(), Orientation::Rotate90);
+        // The GPS IFD pointer and XMP are gone
+        let exif = orientation_exif(6);
+        assert!(optimized.windows(exif.len()).any(|bytes| bytes == exif));
+        assert!(!optimized.windows(2).any(|bytes| bytes == [0x25, 0x88]));
+        assert!(!optimized.windows(12).any(|bytes| bytes == b"ns.adobe.com"));
+    }
+
+    #[test]
+    fn invalid_jpeg_is_not_optimized() {
+        assert_eq!(optimize_jpeg(b"not a jpeg"), None);
+        assert_eq!(optimize_jpeg(&jpeg_with_exif()[..20]), None);
+    }
+
+    #[test]
+    fn svg_keeps_the_doctype_entities() {
+        let svg = br#"<?xml version="1.0"?>
+<!DOCTYPE svg [<!ENTITY ns_svg "http://www.w3.org/2000/svg">]>
+<!-- Generator: Adobe Illustrator -->
+<svg xmlns="&ns_svg;" inkscape:version="1">
+  <metadata>x</metadata>
+  <text> a  b </text>
+</svg>"#;
+        assert_eq!(
+            String::from_utf8(minify_svg(svg).unwrap()).unwrap(),
+            r#"<!DOCTYPE svg [<!ENTITY ns_svg "http://www.w3.org/2000/svg">]><svg xmlns="&ns_svg;"><text> a  b </text></svg>"#
+        );
+    }
+
+    #[test]
+    fn svg_for_html_expands_the_doctype_entities() {
+        let svg = br#"<?xml version="1.0" encoding="utf-8"?>
+<!DOCTYPE svg PUBLIC "-//W3C//DTD SVG 1.1//EN" "http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd" [
+  <!ENTITY ns_svg "http://www.w3.org/2000/svg">
+  <!ENTITY ns_xlink 'http://www.w3.org/1999/xlink'>
+  <!ENTITY name "Tom and Jerry's">
+]>
+<svg xmlns="&ns_svg;" xmlns:xlink="&ns_xlink;" aria-label="&name;">
+  <title>&name; &amp; co</title>
+</svg>"#;
+        assert_eq!(
+            String::from_utf8(minify_svg_for_html(svg).unwrap()).unwrap(),
+            r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" aria-label="Tom and Jerry&apos;s"><title>Tom and Jerry&apos;s &amp; co</title></svg>"#
+        );
+        let external = br#"<!DOCTYPE svg [<!ENTITY logo SYSTEM "logo.svg">]><svg>&logo;</svg>"#;
+        assert_eq!(
+            minify_svg_for_html(external),
+            Err("has an entity logo that can't be inlined".to_string())
+        );
+    }
+}
@@ -0,0 +1,292 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use rolldown::{
+    Bundler, BundlerOptions, BundlerTransformOptions, CodeSplittingMode, Either, InputItem,
+    OutputFormat, Platform, RawMinifyOptions, SourceMapType,
+};
+use rolldown_common::Output;
+use serde_json::Value;
+
+use crate::{
+    config::SourceMapKind,
+    html::{add_attribute, attribute, remove_attribute, rewrite_tags},
+};
+
+// Entries in src/scripts, all of them are bundled to .js
+pub const SCRIPT_EXTENSIONS: [&str; 5] = ["js", "mjs", "jsx", "ts", "tsx"];
+
+// Entry point name, the path under src/scripts without extension, e.g. sub/x for src/scripts/sub/x.ts
+fn entry_name(entry: &Path) -> String {
+    entry
+        .strip_prefix("src/scripts")
+        .unwrap()
+        .with_extension("")
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+fn rolldown_options(
+    entries: &[PathBuf],
+    outdir: &str,
+    source_maps: &SourceMapKind,
+    targets: &[String],
+    splitting: bool,
+) -> BundlerOptions {
+    let input = entries
+        .iter()
+        .map(|entry| InputItem {
+            name: Some(entry_name(entry)),
+            import: format!("./{}", entry.to_str().unwrap()),
+        })
+        .collect();
+    let (format, entry_filenames) = if splitting {
+        (OutputFormat::Esm, "[name].[hash].js")
+    } else {
+        (OutputFormat::Iife, "[name].js")
+    };
+    BundlerOptions {
+        input: Some(input),
+        cwd: Some(std::env::current_dir().unwrap()),
+        dir: Some(outdir.to_string()),
+        entry_filenames: Some(entry_filenames.to_string().into()),
+        chunk_filenames: Some("chunks/[name].[hash].js".to_string().into()),
+        format: Some(format),
+        code_splitting: Some(CodeSplittingMode::Bool(splitting)),
+        platform: Some(Platform::Browser),
+        minify: Some(RawMinifyOptions::Bool(true)),
+        sourcemap: match source_maps {
+            SourceMapKind::None => None,
+            SourceMapKind::External => Some(SourceMapType::File),
+            SourceMapKind::Inline => Some(SourceMapType::Inline),
+            SourceMapKind::Hidden => Some(SourceMapType::Hidden),
+        },
+        transform: Some(BundlerTransformOptions {
+            target: Some(Either::Right(targets.to_vec())),
+            // React.createElement like esbuild does by default
+            jsx: Some(Either::Left("react".to_string())),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+fn run_rolldown(options: BundlerOptions, entries: &str) -> Vec<Output> {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut bundler = Bundler::new(options).unwrap();
+    match runtime.block_on(bundler.write()) {
+        Ok(output) => {
+            for warning in output.warnings.iter() {
+                println!("{}", warning.to_diagnostic());
+            }
+            // Rolldown leaves unresolved npm imports external, esbuild fails instead
+            if output
+                .warnings
+                .iter()
+                .any(|warning| warning.kind().to_string() == "UNRESOLVED_IMPORT")
+            {
+                panic!("failed to bundle {entries}");
+            }
+            output.assets
+        }
+        Err(errors) => {
+            for error in errors.into_vec() {
+                println!("{}", error.to_diagnostic());
+            }
+            panic!("failed to bundle {entries}");
+        }
+    }
+}
+
+/* Bundles and minifies every entry to its own file like esbuild does. The path under src/scripts is kept,
+e.g. src/scripts/sub/x.js -> dist/scripts/sub/x.js, and npm imports are resolved from node_modules */
+pub fn bundle_with_rolldown(
+    entries: &[PathBuf],
+    outdir: &str,
+    source_maps: &SourceMapKind,
+    targets: &[String],
+) {
+    for entry in entries {
+        let options = rolldown_options(
+            std::slice::from_ref(entry),
+            outdir,
+            source_maps,
+            targets,
+            false,
+        );
+        run_rolldown(options, entry.to_str().unwrap());
+    }
+}
+
+// Entry bundled with code splitting, paths are relative to dist/scripts
+pub struct SplitEntry {
+    pub file: String,
+    // Chunks the entry imports, directly or through other chunks
+    pub chunks: Vec<String>,
+}
+
+// Key is the source path of the entry
+pub type SplitEntries = BTreeMap<String, SplitEntry>;
+
+fn with_chunks(
+    entry_files: BTreeMap<String, String>,
+    imports: &BTreeMap<String, Vec<String>>,
+) -> SplitEntries {
+    entry_files
+        .into_iter()
+        .map(|(src, file)| {
+            let mut chunks: Vec<String> = vec![];
+            let mut queue = imports.get(&file).cloned().unwrap_or_default();
+            while let Some(chunk) = queue.pop() {
+                if chunks.contains(&chunk) {
+                    continue;
+                }
+                queue.extend(imports.get(&chunk).cloned().unwrap_or_default());
+                chunks.push(chunk);
+            }
+            chunks.sort();
+            (src, SplitEntry { file, chunks })
+        })
+        .collect()
+}
+
+/* All entries in one build with ESM output, so shared imports go to hashed chunks in dist/scripts/chunks.
+Entries are hashed by rolldown too */
+pub fn bundle_split_with_rolldown(
+    entries: &[PathBuf],
+    outdir: &str,
+    source_maps: &SourceMapKind,
+    targets: &[String],
+) -> SplitEntries {
+    let options = rolldown_options(entries, outdir, source_maps, targets, true);
+    let output = run_rolldown(options, "src/scripts");
+    let src_by_name = entries
+        .iter()
+        .map(|entry| (entry_name(entry), entry.to_str().unwrap().to_string()))
+        .collect::<BTreeMap<String, String>>();
+    let mut entry_files = BTreeMap::new();
+    let mut imports = BTreeMap::new();
+    for output in output {
+        if let Output::Chunk(chunk) = output {
+            if chunk.is_entry {
+                entry_files.insert(
+                    src_by_name[chunk.name.as_str()].clone(),
+                    chunk.filename.to_string(),
+                );
+            }
+            let chunk_imports = chunk.imports.iter().map(|i| i.to_string()).collect();
+            imports.insert(chunk.filename.to_string(), chunk_imports);
+        }
+    }
+    with_chunks(entry_files, &imports)
+}
+
+// esbuild arguments for code splitting, the metafile is read by esbuild_split_entries
+pub fn esbuild_splitting_args(outdir: &str) -> Vec<String> {
+    vec![
+        "--splitting".to_string(),
+        "--format=esm".to_string(),
+        "--entry-names=[dir]/[name].[hash]".to_string(),
+        "--chunk-names=chunks/[name].[hash]".to_string(),
+        format!("--metafile={outdir}/metafile.json"),
+    ]
+}
+
+pub fn esbuild_split_entries(outdir: &str) -> SplitEntries {
+    let metafile_path = format!("{outdir}/metafile.json");
+    let metafile: Value =
+        serde_json::from_str(&fs::read_to_string(&metafile_path).unwrap()).unwrap();
+    fs::remove_file(metafile_path).unwrap();
+    let relative = |path: &str| {
+        path.strip_prefix(&format!("{outdir}/"))
+            .unwrap_or(path)
+            .to_string()
+    };
+    let mut entry_files = BTreeMap::new();
+    let mut imports = BTreeMap::new();
+    for (path, output) in metafile["outputs"].as_object().unwrap() {
+        if let Some(entry_point) = output["entryPoint"].as_str() {
+            entry_files.insert(entry_point.to_string(), relative(path));
+        }
+        let output_imports = output["imports"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|i| i["kind"] == "import-statement")
+            .map(|i| relative(i["path"].as_str().unwrap()))
+            .collect();
+        imports.insert(relative(path), output_imports);
+    }
+    with_chunks(entry_files, &imports)
+}
+
+/* Scripts of split entries are modules, so their script tags get type="module", replacing another
+type like text/javascript, and their chunks are preloaded with modulepreload links in head */
+pub fn module_scripts(html: &str, entries: &SplitEntries) -> String {
+    let mut preloads: Vec<&str> = vec![];
+    let mut html = rewrite_tags(html, "script", |tag, _| {
+        let src = attribute(tag, "src")?;
+        let entry = entries
+            .values()
+            .find(|entry| src == format!("/scripts/{}", entry.file))?;
+        for chunk in entry.chunks.iter() {
+            if !preloads.contains(&chunk.as_str()) {
+                preloads.push(chunk);
+            }
+        }
+        if attribute(tag, "type").is_some_and(|kind| kind.eq_ignore_ascii_case("module")) {
+            return None;
+        }
+        Some(add_attribute(
+            &remove_attribute(tag, "type"),
+            "type",
+            Some("module"),
+        ))
+    });
+    let links = preloads
+        .iter()
+        .map(|chunk| format!("<link rel=\"modulepreload\" href=\"/scripts/{chunk}\" />"))
+        .collect::<String>();
+    match html.find("</head>") {
+        Some(head_end) if !links.is_empty() => {
+            html.insert_str(head_end, &links);
+            html
+        }
+        _ => html,
+    }
+}
+
+// esbuild --sourcemap value, its external means no sourceMappingURL comment
+pub fn esbuild_sourcemap_arg(source_maps: &SourceMapKind) -> Option<String> {
+    match source_maps {
+        SourceMapKind::None => None,
+        SourceMapKind::External => Some("--sourcemap=linked".to_string()),
+        SourceMapKind::Inline => Some("--sourcemap=inline".to_string()),
+        SourceMapKind::Hidden => Some("--sourcemap=external".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_scripts_become_modules() {
+        let entries = SplitEntries::from([(
+            "src/scripts/home.ts".to_string(),
+            SplitEntry {
+                file: "home.abc.js".to_string(),
+                chunks: vec!["chunks/shared.def.js".to_string()],
+            },
+        )]);
+        let html = r#"<head></head><script src="/scripts/home.abc.js" type="text/javascript"></script><script type="module" src="/scripts/home.abc.js"></script><script src="/other.js"></script>"#;
+        assert_eq!(
+            module_scripts(html, &entries),
+            r#"<head><link rel="modulepreload" href="/scripts/chunks/shared.def.js" /></head><script src="/scripts/home.abc.js" type="module"></script><script type="module" src="/scripts/home.abc.js"></script><script src="/other.js"></script>"#
+        );
+    }
+}
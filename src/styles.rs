@@ -0,0 +1,361 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use lightningcss::{
+    bundler::{Bundler, FileProvider, ResolveResult, SourceProvider},
+    dependencies::{Dependency, DependencyOptions},
+    printer::PrinterOptions,
+    rules::{CssRule, CssRuleList},
+    selector::Selector,
+    stylesheet::{MinifyOptions, ParserOptions, StyleSheet},
+    targets::{Browsers, Targets},
+    traits::ToCss,
+};
+use parcel_sourcemap::SourceMap;
+use scraper::Html;
+
+use crate::config::SourceMapKind;
+
+// Files like lightningcss' own provider, but imports from other sites, e.g. fonts, are left as they are
+struct StyleProvider(FileProvider);
+
+impl SourceProvider for StyleProvider {
+    type Error = std::io::Error;
+
+    fn read<'a>(&'a self, file: &Path) -> Result<&'a str, Self::Error> {
+        self.0.read(file)
+    }
+
+    fn resolve(
+        &self,
+        specifier: &str,
+        originating_file: &Path,
+    ) -> Result<ResolveResult, Self::Error> {
+        if specifier.contains("://") || specifier.starts_with("//") {
+            return Ok(ResolveResult::External(specifier.to_string()));
+        }
+        self.0.resolve(specifier, originating_file)
+    }
+}
+
+/* esbuild style targets, e.g. chrome58 or safari11.1. Targets that are not browsers or have no version,
+e.g. es2017 or esnext, are skipped */
+fn browsers(targets: &[String]) -> Browsers {
+    let mut browsers = Browsers::default();
+    for target in targets {
+        let version_start = target
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(target.len());
+        let (name, version) = target.split_at(version_start);
+        if version.is_empty() {
+            continue;
+        }
+        let mut parts = version.split('.').map(|part| {
+            part.parse::<u32>()
+                .unwrap_or_else(|_| panic!("unknown target {target}"))
+        });
+        // Major, minor and patch in one number like lightningcss wants
+        let version = Some(
+            (parts.next().unwrap_or(0) << 16)
+                | (parts.next().unwrap_or(0) << 8)
+                | parts.next().unwrap_or(0),
+        );
+        match name {
+            "chrome" => browsers.chrome = version,
+            "edge" => browsers.edge = version,
+            "firefox" => browsers.firefox = version,
+            "ie" => browsers.ie = version,
+            "ios" => browsers.ios_saf = version,
+            "opera" => browsers.opera = version,
+            "safari" => browsers.safari = version,
+            _ => {}
+        }
+    }
+    browsers
+}
+
+// Path of url relative to the file it is in, e.g. ../media/x.png in src/styles/a.css is src/media/x.png
+fn resolve_url(file_path: &str, url: &str) -> PathBuf {
+    let path = match url.strip_prefix('/') {
+        Some(absolute) => PathBuf::from("src").join(absolute),
+        None => Path::new(file_path).parent().unwrap().join(url),
+    };
+    // Without .. so it matches the keys of the hashes
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::CurDir => {}
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/* Minifies and prefixes the stylesheet. url()s that point to a file in asset_urls are replaced with
+its url, e.g. url(../media/x.png) becomes url(/media/x.<hash>.png), others are left as they are */
+fn print(
+    mut stylesheet: StyleSheet,
+    targets: &[String],
+    asset_urls: &HashMap<String, String>,
+    mut source_map: Option<&mut SourceMap>,
+) -> String {
+    let targets = Targets::from(browsers(targets));
+    stylesheet
+        .minify(MinifyOptions {
+            targets,
+            ..Default::default()
+        })
+        .unwrap_or_else(|e| panic!("failed to minify css: {e}"));
+    let result = stylesheet
+        .to_css(PrinterOptions {
+            minify: true,
+            targets,
+            analyze_dependencies: Some(DependencyOptions {
+                remove_imports: false,
+            }),
+            source_map: source_map.as_deref_mut(),
+            ..Default::default()
+        })
+        .unwrap_or_else(|e| panic!("failed to print css: {e}"));
+    let mut css = result.code;
+    for dependency in result.dependencies.unwrap_or_default() {
+        let (placeholder, url) = match dependency {
+            Dependency::Url(dep) => {
+                // Query and hash stay, e.g. fonts with ?#iefix
+                let end = dep.url.find(['?', '#']).unwrap_or(dep.url.len());
+                let path = resolve_url(&dep.loc.file_path, &dep.url[..end]);
+                let url = match asset_urls.get(path.to_str().unwrap()) {
+                    Some(asset_url) => format!("{asset_url}{}", &dep.url[end..]),
+                    None => dep.url,
+                };
+                (dep.placeholder, url)
+            }
+            // Imports that were not inlined, e.g. fonts from another site
+            Dependency::Import(dep) => (dep.placeholder, dep.url),
+        };
+        let Some(start) = css.find(&placeholder) else {
+            continue;
+        };
+        // Mappings after the url move by the difference in length
+        if let Some(source_map) = source_map.as_deref_mut() {
+            let line = css[..start].matches('\n').count();
+            let line_start = css[..start].rfind('\n').map_or(0, |i| i + 1);
+            let column = start - line_start + placeholder.len();
+            source_map
+                .offset_columns(
+                    line as u32,
+                    column as u32,
+                    url.len() as i64 - placeholder.len() as i64,
+                )
+                .unwrap();
+        }
+        css.replace_range(start..start + placeholder.len(), &url);
+    }
+    css
+}
+
+/* Source map of a stylesheet written like the bundlers write them for scripts: a .map file next to
+dist_path with a sourceMappingURL comment, the map as a data url, or a .map file without the comment.
+Returns the css with the comment. rename_to_hashed renames the map with the stylesheet */
+pub fn add_source_map(
+    css: String,
+    dist_path: &Path,
+    mut source_map: SourceMap,
+    kind: &SourceMapKind,
+) -> String {
+    // Sources are relative to the project root, e.g. ../../src/styles/a.css for dist/styles/a.css
+    let depth = dist_path.parent().unwrap().components().count();
+    let source_root = "../".repeat(depth);
+    let json = source_map.to_json(Some(&source_root)).unwrap();
+    let mut map: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&json).unwrap();
+    let filename = dist_path.file_name().unwrap().to_str().unwrap();
+    map.insert("file".to_string(), filename.into());
+    let json = serde_json::to_string(&map).unwrap();
+    match kind {
+        SourceMapKind::None => css,
+        SourceMapKind::Inline => format!(
+            "{css}\n/*# sourceMappingURL=data:application/json;base64,{} */",
+            STANDARD.encode(json)
+        ),
+        SourceMapKind::External => {
+            fs::write(dist_path.with_file_name(format!("{filename}.map")), json).unwrap();
+            format!("{css}\n/*# sourceMappingURL={filename}.map */")
+        }
+        SourceMapKind::Hidden => {
+            fs::write(dist_path.with_file_name(format!("{filename}.map")), json).unwrap();
+            css
+        }
+    }
+}
+
+// Stylesheet in src/styles with its @imports inlined
+pub fn process_style(
+    path: &Path,
+    targets: &[String],
+    asset_urls: &HashMap<String, String>,
+    mut source_map: Option<&mut SourceMap>,
+) -> String {
+    let fs = StyleProvider(FileProvider::new());
+    let mut bundler = Bundler::new(&fs, source_map.as_deref_mut(), ParserOptions::default());
+    let stylesheet = bundler
+        .bundle(path)
+        .unwrap_or_else(|e| panic!("failed to bundle {}: {e}", path.to_str().unwrap()));
+    print(stylesheet, targets, asset_urls, source_map)
+}
+
+// Tailwind output, urls in it are relative to src/styles/tailwind.css
+pub fn process_tailwind_output(
+    css: &str,
+    targets: &[String],
+    asset_urls: &HashMap<String, String>,
+    mut source_map: Option<&mut SourceMap>,
+) -> String {
+    let filename = "src/styles/tailwind.css";
+    let options = ParserOptions {
+        filename: filename.to_string(),
+        ..Default::default()
+    };
+    let stylesheet = StyleSheet::parse(css, options)
+        .unwrap_or_else(|e| panic!("failed to parse tailwind output: {e}"));
+    // The map points to the tailwind output, the source of tailwind.css is only its directives
+    if let Some(source_map) = source_map.as_deref_mut() {
+        let index = source_map.add_source(filename);
+        source_map.set_source_content(index as usize, css).unwrap();
+    }
+    print(stylesheet, targets, asset_urls, source_map)
+}
+
+/* Selector without pseudo-classes and pseudo-elements so it can be matched against the page, e.g.
+.hover\:underline:hover becomes .hover\:underline. A compound that had only them becomes * */
+fn without_pseudo(selector: &str) -> String {
+    let mut stripped = String::new();
+    let mut chars = selector.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                stripped.push(c);
+                stripped.extend(chars.next());
+            }
+            // Attribute selectors can have colons in their values
+            '[' => {
+                stripped.push(c);
+                let mut quote = None;
+                for c in chars.by_ref() {
+                    stripped.push(c);
+                    match (quote, c) {
+                        (None, '"' | '\'') => quote = Some(c),
+                        (Some(q), c) if q == c => quote = None,
+                        (None, ']') => break,
+                        _ => {}
+                    }
+                }
+            }
+            ':' => {
+                if stripped.is_empty() || stripped.ends_with([' ', '>', '+', '~']) {
+                    stripped.push('*');
+                }
+                // Name and arguments, e.g. :not(.a)
+                let mut depth = 0;
+                while let Some(&next) = chars.peek() {
+                    match next {
+                        '(' => depth += 1,
+                        ')' => depth -= 1,
+                        c if depth == 0 && (c.is_whitespace() || ">+~.#[:,".contains(c)) => break,
+                        _ => {}
+                    }
+                    chars.next();
+                }
+            }
+            c => stripped.push(c),
+        }
+    }
+    stripped
+}
+
+fn matches(selector: &Selector, page: &Html) -> bool {
+    let Ok(selector) = selector.to_css_string(PrinterOptions::default()) else {
+        return true;
+    };
+    match scraper::Selector::parse(&without_pseudo(&selector)) {
+        Ok(selector) => page.select(&selector).next().is_some(),
+        // Selectors that can't be matched are kept
+        Err(_) => true,
+    }
+}
+
+// Keeps the style rules with a selector that matches the page, at-rules like @font-face are kept as they are
+fn retain_used_rules(rules: &mut CssRuleList, page: &Html) {
+    let retain_nested = |rules: &mut CssRuleList| {
+        retain_used_rules(rules, page);
+        !rules.0.is_empty()
+    };
+    rules.0.retain_mut(|rule| match rule {
+        CssRule::Style(style) => style.selectors.0.iter().any(|s| matches(s, page)),
+        CssRule::Media(media) => retain_nested(&mut media.rules),
+        CssRule::Supports(supports) => retain_nested(&mut supports.rules),
+        CssRule::LayerBlock(layer) => retain_nested(&mut layer.rules),
+        CssRule::Container(container) => retain_nested(&mut container.rules),
+        _ => true,
+    });
+}
+
+// Built stylesheet for critical_css, parsed once and used for every page
+pub fn parse_built_style(css: &str) -> StyleSheet<'_> {
+    StyleSheet::parse(css, ParserOptions::default())
+        .unwrap_or_else(|e| panic!("failed to parse css: {e}"))
+}
+
+/* Rules of a built stylesheet that the page uses. Rules for states like :hover and for elements that
+scripts add later are left out when nothing in the page matches them otherwise */
+pub fn critical_css(stylesheet: &StyleSheet, html: &str) -> String {
+    let mut stylesheet = stylesheet.clone();
+    retain_used_rules(&mut stylesheet.rules, &Html::parse_document(html));
+    stylesheet
+        .to_css(PrinterOptions {
+            minify: true,
+            ..Default::default()
+        })
+        .unwrap_or_else(|e| panic!("failed to print css: {e}"))
+        .code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn targets_without_a_version_are_skipped() {
+        let targets = ["esnext", "es2017", "chrome58", "safari11.1"].map(String::from);
+        let browsers = browsers(&targets);
+        assert_eq!(browsers.chrome, Some(58 << 16));
+        assert_eq!(browsers.safari, Some((11 << 16) | (1 << 8)));
+        assert_eq!(browsers.firefox, None);
+    }
+
+    #[test]
+    fn source_map_follows_rewritten_urls() {
+        let css = "a{background:url(../media/x.png)}\nb{color:red}";
+        let asset_urls = HashMap::from([(
+            "src/media/x.png".to_string(),
+            "/media/x.0123456789abcdef.png".to_string(),
+        )]);
+        let mut source_map = SourceMap::new("/");
+        let printed = process_tailwind_output(css, &[], &asset_urls, Some(&mut source_map));
+        assert_eq!(
+            printed,
+            "a{background:url(\"/media/x.0123456789abcdef.png\")}b{color:red}"
+        );
+        let column = printed.find("b{").unwrap() as u32;
+        let mapping = source_map.find_closest_mapping(0, column).unwrap();
+        assert_eq!(mapping.generated_column, column);
+        assert_eq!(mapping.original.unwrap().original_line, 1);
+    }
+}
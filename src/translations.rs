@@ -0,0 +1,294 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+};
+
+use clap::ValueEnum;
+use quick_xml::{escape::escape, events::Event, Reader};
+use serde_json::{Map, Value};
+
+use crate::{
+    config::IntlConfig,
+    intl::{file_for_key, load_intl, Intl},
+};
+
+#[derive(Clone, ValueEnum)]
+pub enum TranslationFormat {
+    Csv,
+    Xliff,
+}
+
+// Dotted keys and values of the string translations in file order
+fn string_leaves(prefix: &str, value: &Value, leaves: &mut Vec<(String, String)>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let path = if prefix.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                string_leaves(&path, value, leaves);
+            }
+        }
+        Value::String(s) => leaves.push((prefix.to_string(), s.to_string())),
+        _ => {}
+    }
+}
+
+// Languages in intl order, the default language first
+fn languages(intl: &Intl) -> Vec<String> {
+    let mut langs = intl.files.keys().cloned().collect::<Vec<String>>();
+    if let Some(default_lang) = &intl.default_lang {
+        langs.retain(|lang| lang != default_lang);
+        langs.insert(0, default_lang.to_string());
+    }
+    langs
+}
+
+// Key is translation key, Value is translation per language. Keys are in the order of the first language
+fn translation_table(intl: &Intl, langs: &[String]) -> Vec<(String, BTreeMap<String, String>)> {
+    let mut table: Vec<(String, BTreeMap<String, String>)> = vec![];
+    for lang in langs {
+        let mut leaves = vec![];
+        string_leaves("", &intl.map[lang], &mut leaves);
+        for (key, value) in leaves {
+            match table.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, values)) => {
+                    values.insert(lang.to_string(), value);
+                }
+                None => table.push((key, BTreeMap::from([(lang.to_string(), value)]))),
+            }
+        }
+    }
+    table
+}
+
+fn export_csv(intl: &Intl, output: &str) {
+    let langs = languages(intl);
+    let mut writer = csv::Writer::from_path(output).unwrap();
+    let mut header = vec!["key".to_string()];
+    header.extend(langs.iter().cloned());
+    writer.write_record(&header).unwrap();
+    for (key, values) in translation_table(intl, &langs) {
+        let mut record = vec![key];
+        for lang in langs.iter() {
+            record.push(values.get(lang).cloned().unwrap_or_default());
+        }
+        writer.write_record(&record).unwrap();
+    }
+    writer.flush().unwrap();
+    println!("Wrote {output}");
+}
+
+/* XLIFF is bilingual, so every other language gets its own file next to output,
+e.g. translations.xlf becomes translations.en.xlf */
+fn export_xliff(intl: &Intl, output: &str) {
+    let langs = languages(intl);
+    let source_lang = match langs.first() {
+        Some(lang) => lang,
+        None => {
+            println!("No translations in src/intl");
+            return;
+        }
+    };
+    let table = translation_table(intl, &langs);
+    let stem = output
+        .strip_suffix(".xlf")
+        .or_else(|| output.strip_suffix(".xliff"))
+        .unwrap_or(output);
+    for target_lang in langs.iter().skip(1) {
+        let mut xliff = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<xliff xmlns=\"urn:oasis:names:tc:xliff:document:2.0\" version=\"2.0\" srcLang=\"{source_lang}\" trgLang=\"{target_lang}\">\n  <file id=\"intl\">\n"
+        );
+        for (key, values) in table.iter() {
+            let source = values
+                .get(source_lang)
+                .map(String::as_str)
+                .unwrap_or_default();
+            xliff += &format!(
+                "    <unit id=\"{}\">\n      <segment>\n",
+                escape(key.as_str())
+            );
+            xliff += &format!("        <source>{}</source>\n", escape(source));
+            if let Some(target) = values.get(target_lang) {
+                xliff += &format!("        <target>{}</target>\n", escape(target.as_str()));
+            }
+            xliff += "      </segment>\n    </unit>\n";
+        }
+        xliff += "  </file>\n</xliff>\n";
+        let path = format!("{stem}.{target_lang}.xlf");
+        fs::write(&path, xliff).unwrap();
+        println!("Wrote {path}");
+    }
+}
+
+pub fn export_translations(config: &IntlConfig, format: &TranslationFormat, output: Option<&str>) {
+    let intl = load_intl(config);
+    match format {
+        TranslationFormat::Csv => export_csv(&intl, output.unwrap_or("translations.csv")),
+        TranslationFormat::Xliff => export_xliff(&intl, output.unwrap_or("translations.xlf")),
+    }
+}
+
+// Key is language, Value is translations by key
+type Imported = BTreeMap<String, Vec<(String, String)>>;
+
+fn read_csv(file: &str, imported: &mut Imported) {
+    let mut reader = csv::Reader::from_path(file).unwrap();
+    let header = reader.headers().unwrap().clone();
+    for record in reader.records() {
+        let record = record.unwrap();
+        let key = &record[0];
+        for (lang, value) in header.iter().zip(record.iter()).skip(1) {
+            imported
+                .entry(lang.to_string())
+                .or_default()
+                .push((key.to_string(), value.to_string()));
+        }
+    }
+}
+
+fn read_xliff(file: &str, imported: &mut Imported) {
+    let content = fs::read_to_string(file).unwrap();
+    let mut reader = Reader::from_str(&content);
+    let mut target_lang = None;
+    let mut unit_id = None;
+    let mut target: Option<String> = None;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) => match e.local_name().as_ref() {
+                b"xliff" => {
+                    target_lang = e
+                        .try_get_attribute("trgLang")
+                        .unwrap()
+                        .map(|attr| attr.unescape_value().unwrap().to_string());
+                }
+                b"unit" => {
+                    unit_id = e
+                        .try_get_attribute("id")
+                        .unwrap()
+                        .map(|attr| attr.unescape_value().unwrap().to_string());
+                }
+                b"target" => target = Some(String::new()),
+                _ => {}
+            },
+            Ok(Event::Text(e)) => {
+                if let Some(target) = target.as_mut() {
+                    target.push_str(&e.unescape().unwrap());
+                }
+            }
+            Ok(Event::End(e)) if e.local_name().as_ref() == b"target" => {
+                let lang = match &target_lang {
+                    Some(lang) => lang,
+                    None => panic!("{file} has no trgLang"),
+                };
+                if let (Some(key), Some(value)) = (&unit_id, target.take()) {
+                    imported
+                        .entry(lang.to_string())
+                        .or_default()
+                        .push((key.to_string(), value));
+                }
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => {}
+            Err(e) => panic!("failed to read {file}: {e}"),
+        }
+    }
+}
+
+fn set_path(map: &mut Map<String, Value>, path: &[&str], value: &str) -> bool {
+    let (key, rest) = path.split_first().unwrap();
+    if rest.is_empty() {
+        if map.get(*key).and_then(Value::as_str) == Some(value) {
+            return false;
+        }
+        map.insert(key.to_string(), value.into());
+        return true;
+    }
+    let child = map
+        .entry(key.to_string())
+        .or_insert(Value::Object(Map::new()));
+    if !child.is_object() {
+        panic!(
+            "cannot set {} because {key} is not an object",
+            path.join(".")
+        );
+    }
+    set_path(child.as_object_mut().unwrap(), rest, value)
+}
+
+/* Writes the translations back to the intl files they belong to. Empty values are skipped,
+other keys and their order stay untouched and new keys are added to the end */
+pub fn import_translations(config: &IntlConfig, files: &[String]) {
+    let intl = load_intl(config);
+    let mut imported = Imported::new();
+    for file in files {
+        if file.ends_with(".csv") {
+            read_csv(file, &mut imported);
+        } else if file.ends_with(".xlf") || file.ends_with(".xliff") {
+            read_xliff(file, &mut imported);
+        } else {
+            panic!("unknown translation file {file}, expected .csv, .xlf or .xliff");
+        }
+    }
+
+    // Key is intl file, Value is its content
+    let mut contents: BTreeMap<String, Map<String, Value>> = BTreeMap::new();
+    let mut changed = BTreeSet::new();
+    let no_files = vec![];
+    for (lang, translations) in imported {
+        let lang_files = intl.files.get(&lang).unwrap_or(&no_files);
+        for (key, value) in translations {
+            if value.is_empty() {
+                continue;
+            }
+            let (file, namespace_len) = file_for_key(&lang, lang_files, &key);
+            let content = contents.entry(file.clone()).or_insert_with(|| {
+                if Path::new(&file).exists() {
+                    serde_json::from_str(&fs::read_to_string(&file).unwrap()).unwrap()
+                } else {
+                    Map::new()
+                }
+            });
+            let path = key.split('.').skip(namespace_len).collect::<Vec<&str>>();
+            if !set_path(content, &path, &value) {
+                continue;
+            }
+            println!("{file}: {key}");
+            changed.insert(file);
+        }
+    }
+    // Files without changes are not written, so their formatting stays as it is
+    for file in changed {
+        let content = &contents[&file];
+        let path = Path::new(&file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, serde_json::to_string_pretty(content).unwrap() + "\n").unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn set_path_reports_only_changes() {
+        let mut map = json!({ "nav": { "home": "Koti" }, "title": "Moi" })
+            .as_object()
+            .unwrap()
+            .clone();
+        assert!(!set_path(&mut map, &["nav", "home"], "Koti"));
+        assert!(!set_path(&mut map, &["title"], "Moi"));
+        assert!(set_path(&mut map, &["nav", "about"], "Meistä"));
+        assert!(set_path(&mut map, &["title"], "Hei"));
+        // New keys go to the end, others keep their place
+        assert_eq!(
+            serde_json::to_string(&map).unwrap(),
+            r#"{"nav":{"home":"Koti","about":"Meistä"},"title":"Hei"}"#
+        );
+    }
+}